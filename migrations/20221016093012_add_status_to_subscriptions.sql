@@ -0,0 +1,4 @@
+-- Add migration script here
+-- Add a nullable status column first: existing rows keep working
+-- while old instances of the application are still running.
+ALTER TABLE subscriptions ADD COLUMN status TEXT NULL;
@@ -0,0 +1,14 @@
+-- Add migration script here
+-- Tokens are now single-use and expire after a while.
+BEGIN;
+    ALTER TABLE subscription_tokens
+        ADD COLUMN created_at timestamptz NOT NULL DEFAULT now();
+    ALTER TABLE subscription_tokens ADD COLUMN expires_at timestamptz NULL;
+    -- Backfill 'expires_at' for historical entries
+    UPDATE subscription_tokens
+        SET expires_at = created_at + interval '24 hours'
+        WHERE expires_at IS NULL;
+    ALTER TABLE subscription_tokens ALTER COLUMN expires_at SET NOT NULL;
+    -- NULL until the token has been used to confirm the subscription
+    ALTER TABLE subscription_tokens ADD COLUMN consumed_at timestamptz NULL;
+COMMIT;
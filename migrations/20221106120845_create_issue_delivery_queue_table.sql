@@ -0,0 +1,9 @@
+-- Add migration script here
+-- One row per (issue, recipient) still waiting to be delivered.
+-- Workers delete a row once the email has been handed to the provider.
+CREATE TABLE issue_delivery_queue (
+    newsletter_issue_id uuid NOT NULL
+        REFERENCES newsletter_issues (newsletter_issue_id),
+    subscriber_email TEXT NOT NULL,
+    PRIMARY KEY(newsletter_issue_id, subscriber_email)
+);
@@ -0,0 +1,5 @@
+-- Add migration script here
+-- Failed deliveries are retried with an exponential backoff:
+-- workers ignore a task until 'execute_after' has passed.
+ALTER TABLE issue_delivery_queue ADD COLUMN n_retries SMALLINT NOT NULL DEFAULT 0;
+ALTER TABLE issue_delivery_queue ADD COLUMN execute_after timestamptz NOT NULL DEFAULT now();
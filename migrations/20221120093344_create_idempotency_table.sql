@@ -0,0 +1,16 @@
+-- Add migration script here
+-- Responses of requests carrying an 'Idempotency-Key' header,
+-- replayed verbatim when the same key shows up again.
+CREATE TYPE header_pair AS (
+    name TEXT,
+    value BYTEA
+);
+CREATE TABLE idempotency (
+    idempotency_key TEXT NOT NULL,
+    -- NULL until the first request has completed
+    response_status_code SMALLINT NULL,
+    response_headers header_pair[] NULL,
+    response_body BYTEA NULL,
+    created_at timestamptz NOT NULL,
+    PRIMARY KEY(idempotency_key)
+);
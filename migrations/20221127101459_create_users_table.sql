@@ -0,0 +1,8 @@
+-- Add migration script here
+-- Admins allowed to publish newsletter issues.
+-- 'password_hash' is an Argon2id hash in PHC string format (salt included).
+CREATE TABLE users(
+    user_id uuid PRIMARY KEY,
+    username TEXT NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL
+);
@@ -0,0 +1,8 @@
+-- Add migration script here
+-- Idempotency keys are now scoped to the user who sent the request.
+-- Saved responses are only useful to replay short-lived retries,
+-- so we can start from an empty table.
+TRUNCATE idempotency;
+ALTER TABLE idempotency ADD COLUMN user_id uuid NOT NULL REFERENCES users (user_id);
+ALTER TABLE idempotency DROP CONSTRAINT idempotency_pkey;
+ALTER TABLE idempotency ADD PRIMARY KEY (user_id, idempotency_key);
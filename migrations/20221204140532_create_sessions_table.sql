@@ -0,0 +1,9 @@
+-- Add migration script here
+-- Admin sessions, keyed by the value of the session cookie.
+-- 'state' holds the session data as a JSON object.
+CREATE TABLE sessions(
+    session_key TEXT PRIMARY KEY,
+    state TEXT NOT NULL,
+    expires_at timestamptz NOT NULL
+);
+CREATE INDEX sessions_expires_at_idx ON sessions (expires_at);
@@ -0,0 +1,14 @@
+-- Add migration script here
+-- Addresses we must stop emailing, as reported by Postmark's webhooks
+-- (hard bounces, spam complaints and manual suppressions) or because
+-- the provider rejected them when we tried to send an issue.
+-- Addresses are stored lowercased.
+-- The subscription itself is left untouched: the address can be reactivated.
+CREATE TABLE suppressions(
+    email TEXT PRIMARY KEY,
+    -- 'hard_bounce', 'spam_complaint', 'manual' or 'rejected'
+    reason TEXT NOT NULL,
+    -- what the provider told us, for whoever investigates
+    details TEXT NULL,
+    suppressed_at timestamptz NOT NULL
+);
@@ -0,0 +1,18 @@
+-- Add migration script here
+-- Open and click tracking is opt-in, issue by issue...
+ALTER TABLE newsletter_issues ADD COLUMN track_opens BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE newsletter_issues ADD COLUMN track_clicks BOOLEAN NOT NULL DEFAULT false;
+-- ...and subscribers can opt out of it altogether.
+ALTER TABLE subscriptions ADD COLUMN tracking_opt_out BOOLEAN NOT NULL DEFAULT false;
+CREATE TABLE tracking_events(
+    event_id uuid PRIMARY KEY,
+    newsletter_issue_id uuid NOT NULL
+        REFERENCES newsletter_issues (newsletter_issue_id),
+    subscriber_email TEXT NOT NULL,
+    -- 'open' or 'click'
+    kind TEXT NOT NULL,
+    -- the link that was clicked
+    url TEXT NULL,
+    occurred_at timestamptz NOT NULL
+);
+CREATE INDEX tracking_events_issue_idx ON tracking_events (newsletter_issue_id, kind);
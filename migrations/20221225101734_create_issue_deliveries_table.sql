@@ -0,0 +1,12 @@
+-- Add migration script here
+-- The deliveries the email provider accepted: the queue forgets about them
+-- once they are sent, the delivery statistics do not.
+CREATE TABLE issue_deliveries(
+    newsletter_issue_id uuid NOT NULL
+        REFERENCES newsletter_issues (newsletter_issue_id),
+    subscriber_email TEXT NOT NULL,
+    sent_at timestamptz NOT NULL,
+    -- set by Postmark's bounce webhooks, hard and soft bounces alike
+    bounced_at timestamptz NULL,
+    PRIMARY KEY(newsletter_issue_id, subscriber_email)
+);
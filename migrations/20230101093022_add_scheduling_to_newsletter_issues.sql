@@ -0,0 +1,15 @@
+-- Add migration script here
+-- Issues can be written now and sent later.
+-- 'scheduled_for' is NULL for issues sent right away.
+ALTER TABLE newsletter_issues ADD COLUMN scheduled_for timestamptz NULL;
+-- Deliver at the wall-clock time of 'scheduled_for' (read in UTC)
+-- in each subscriber's timezone, e.g. 9am wherever they are.
+ALTER TABLE newsletter_issues ADD COLUMN send_in_subscriber_timezone BOOLEAN NOT NULL DEFAULT false;
+-- When the delivery tasks were queued: the issue can no longer
+-- be rescheduled or cancelled after that.
+ALTER TABLE newsletter_issues ADD COLUMN enqueued_at timestamptz NULL;
+ALTER TABLE newsletter_issues ADD COLUMN cancelled_at timestamptz NULL;
+-- Every issue published so far was queued right away
+UPDATE newsletter_issues SET enqueued_at = published_at;
+-- IANA name, e.g. 'Europe/Paris'
+ALTER TABLE subscriptions ADD COLUMN timezone TEXT NULL;
@@ -0,0 +1,162 @@
+use super::{validate_credentials, AuthError, Credentials};
+use crate::session_state::TypedSession;
+use crate::utils::{e500, see_other};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::header::{HeaderMap, HeaderValue};
+use actix_web::http::{header, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::{web, FromRequest, HttpMessage, HttpResponse};
+use anyhow::Context;
+use secrecy::Secret;
+use sqlx::PgPool;
+use std::ops::Deref;
+use uuid::Uuid;
+
+// The id of the authenticated user, available to handlers
+// behind the middleware as 'web::ReqData<UserId>'.
+#[derive(Copy, Clone, Debug)]
+pub struct UserId(Uuid);
+
+impl std::fmt::Display for UserId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Deref for UserId {
+    type Target = Uuid;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+// Guard for the admin routes: requests must carry valid HTTP Basic
+// credentials, otherwise we reply with a 401 and a 'WWW-Authenticate'
+// header so that clients know how to authenticate.
+pub async fn reject_unauthenticated_users(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let credentials = match basic_authentication(req.headers()) {
+        Ok(credentials) => credentials,
+        Err(e) => return Err(unauthorized(e)),
+    };
+    let pool = {
+        let (http_request, payload) = req.parts_mut();
+        web::Data::<PgPool>::from_request(http_request, payload).await
+    }?;
+    match validate_credentials(credentials, &pool).await {
+        Ok(user_id) => {
+            req.extensions_mut().insert(UserId(user_id));
+            next.call(req).await
+        }
+        Err(AuthError::InvalidCredentials(e)) => Err(unauthorized(e)),
+        Err(e @ AuthError::UnexpectedError(_)) => Err(e500(e)),
+    }
+}
+
+// Guard for the admin pages: visitors without a session
+// are sent to the login form.
+pub async fn reject_anonymous_users(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let session = {
+        let (http_request, payload) = req.parts_mut();
+        TypedSession::from_request(http_request, payload).await
+    }?;
+    match session.get_user_id().map_err(e500)? {
+        Some(user_id) => {
+            req.extensions_mut().insert(UserId(user_id));
+            next.call(req).await
+        }
+        None => {
+            let response = see_other("/login");
+            let e = anyhow::anyhow!("The user has not logged in");
+            Err(InternalError::from_response(e, response).into())
+        }
+    }
+}
+
+fn unauthorized(e: anyhow::Error) -> actix_web::Error {
+    let mut response = HttpResponse::new(StatusCode::UNAUTHORIZED);
+    let header_value = HeaderValue::from_str(r#"Basic realm="publish""#).unwrap();
+    response
+        .headers_mut()
+        .insert(header::WWW_AUTHENTICATE, header_value);
+    InternalError::from_response(e, response).into()
+}
+
+pub fn basic_authentication(headers: &HeaderMap) -> Result<Credentials, anyhow::Error> {
+    // The header value, if present, must be a valid UTF8 string
+    let header_value = headers
+        .get("Authorization")
+        .context("The 'Authorization' header was missing")?
+        .to_str()
+        .context("The 'Authorization' header was not a valid UTF8 string.")?;
+    let base64encoded_segment = header_value
+        .strip_prefix("Basic ")
+        .context("The authorization scheme was not 'Basic'.")?;
+    let decoded_bytes = base64::decode_config(base64encoded_segment, base64::STANDARD)
+        .context("Failed to base64-decode 'Basic' credentials.")?;
+    let decoded_credentials = String::from_utf8(decoded_bytes)
+        .context("The decoded credential string is not valid UTF8.")?;
+
+    // Split into two segments, using ':' as delimiter
+    let mut credentials = decoded_credentials.splitn(2, ':');
+    let username = credentials
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("A username must be provided in 'Basic' auth."))?
+        .to_string();
+    let password = credentials
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("A password must be provided in 'Basic' auth."))?
+        .to_string();
+
+    Ok(Credentials {
+        username,
+        password: Secret::new(password),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::basic_authentication;
+    use actix_web::http::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+    use claim::{assert_err, assert_ok};
+    use secrecy::ExposeSecret;
+
+    fn headers(authorization: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(authorization).unwrap());
+        headers
+    }
+
+    #[test]
+    fn valid_basic_credentials_are_parsed() {
+        let encoded = base64::encode("admin:pass:word");
+        let credentials = assert_ok!(basic_authentication(&headers(&format!("Basic {}", encoded))));
+        assert_eq!(credentials.username, "admin");
+        // Only the first ':' separates the username from the password
+        assert_eq!(credentials.password.expose_secret(), "pass:word");
+    }
+
+    #[test]
+    fn a_missing_authorization_header_is_rejected() {
+        assert_err!(basic_authentication(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn a_non_basic_scheme_is_rejected() {
+        assert_err!(basic_authentication(&headers("Bearer a-token")));
+    }
+
+    #[test]
+    fn credentials_without_a_password_are_rejected() {
+        let encoded = base64::encode("admin");
+        assert_err!(basic_authentication(&headers(&format!("Basic {}", encoded))));
+    }
+}
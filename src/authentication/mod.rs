@@ -0,0 +1,9 @@
+mod middleware;
+mod password;
+
+pub use middleware::{
+    basic_authentication, reject_anonymous_users, reject_unauthenticated_users, UserId,
+};
+pub use password::{
+    compute_password_hash, set_admin_password, validate_credentials, AuthError, Credentials,
+};
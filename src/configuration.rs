@@ -3,15 +3,39 @@ use serde_aux::field_attributes::deserialize_number_from_string;
 use sqlx::postgres::{PgConnectOptions, PgSslMode};
 use sqlx::ConnectOptions;
 use crate::domain::SubscriberEmail;
+use crate::email_client::{
+    DevMailbox, EmailSender, FailoverEmailClient, PostmarkEmailClient, SmtpEmailClient, Throttle,
+};
+use std::sync::Arc;
+use crate::email_templates::EmailTemplates;
+use crate::issue_delivery_worker::RetryPolicy;
+use reqwest::Url;
 // define the actix web server + Postgres DB configs
-#[derive(serde::Deserialize)]
+#[derive(serde::Deserialize, Clone)]
 pub struct Settings {
     pub database: DatabaseSettings,
     pub application: ApplicationSettings,
     pub email_client: EmailClientSettings,
+    pub email_templates: EmailTemplatesSettings,
+    pub issue_delivery: IssueDeliverySettings,
+    pub webhooks: WebhookSettings,
 }
 
-#[derive(serde::Deserialize)]
+#[derive(serde::Deserialize, Clone)]
+pub struct WebhookSettings {
+    pub postmark: WebhookCredentials,
+}
+
+// Postmark can authenticate its webhooks with HTTP Basic credentials
+// embedded in the webhook URL, or with a custom header carrying the secret:
+// we accept both.
+#[derive(serde::Deserialize, Clone)]
+pub struct WebhookCredentials {
+    pub username: String,
+    pub secret: Secret<String>,
+}
+
+#[derive(serde::Deserialize, Clone)]
 pub struct DatabaseSettings {
     pub username: String,
     pub password: Secret<String>,
@@ -23,24 +47,243 @@ pub struct DatabaseSettings {
     pub require_ssl: bool,
 }
 
-#[derive(serde::Deserialize)]
+#[derive(serde::Deserialize, Clone)]
 pub struct ApplicationSettings {
     #[serde(deserialize_with = "deserialize_number_from_string")]
     pub port: u16,
     pub host: String,
+    // the public url of the application, used to build the links
+    // we send out in emails (e.g. the confirmation link)
+    pub base_url: String,
+    // how long a confirmation link stays valid
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub subscription_token_ttl_hours: i64,
+    // signs the session cookie, must be at least 64 bytes long
+    pub hmac_secret: Secret<String>,
+    // the password of the 'admin' user, set on startup.
+    // Nobody can log in as 'admin' until it is provided.
+    #[serde(default)]
+    pub admin_password: Option<Secret<String>>,
 }
 
-#[derive(serde::Deserialize)]
+impl ApplicationSettings {
+    pub fn subscription_token_ttl(&self) -> chrono::Duration {
+        chrono::Duration::hours(self.subscription_token_ttl_hours)
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct EmailTemplatesSettings {
+    // see 'EmailTemplates::load' for what it must contain
+    pub directory: String,
+}
+
+impl EmailTemplatesSettings {
+    pub fn load(&self) -> Result<EmailTemplates, anyhow::Error> {
+        EmailTemplates::load(&self.directory)
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
 pub struct EmailClientSettings {
-    pub base_url: String,
+    // which backend sends our emails
+    pub provider: EmailProvider,
+    // tried in order when 'provider' is failing
+    #[serde(default)]
+    pub fallback_providers: Vec<EmailProvider>,
+    // when to give up on a provider, and when to try it again
+    #[serde(default)]
+    pub failover: FailoverSettings,
+    // Postmark's API, e.g. "https://api.postmarkapp.com".
+    // Parsed once here, so that a typo stops the application at startup
+    // instead of failing every email we send.
+    #[serde(deserialize_with = "deserialize_base_url")]
+    pub base_url: Url,
     pub sender_email: String,
     // new (secret) configuration value
     pub authorization_token: Secret<String>,
     // new config value
     pub timeout_milliseconds: u64,
+    // client-side limits for the Postmark API; no limits if left out
+    pub rate_limit: Option<RateLimitSettings>,
+    // only required when 'provider' is 'smtp'
+    pub smtp: Option<SmtpSettings>,
+    // only required when 'provider' is 'dev_mailbox'
+    pub dev_mailbox: Option<DevMailboxSettings>,
+}
+
+#[derive(serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EmailProvider {
+    Postmark,
+    Smtp,
+    // local development only: emails are written to disk
+    DevMailbox,
+}
+
+impl std::fmt::Display for EmailProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            EmailProvider::Postmark => "postmark",
+            EmailProvider::Smtp => "smtp",
+            EmailProvider::DevMailbox => "dev_mailbox",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct FailoverSettings {
+    // consecutive transient failures before we move on to the next provider
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub failure_threshold: u32,
+    // how often we check whether a failing provider has recovered
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub probe_interval_seconds: u64,
+}
+
+impl Default for FailoverSettings {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            probe_interval_seconds: 30,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct RateLimitSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub requests_per_second: u32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_in_flight: usize,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct DevMailboxSettings {
+    // where the '.eml' files and their index are written
+    pub directory: String,
+}
+
+// An SMTP relay, e.g. an internal MTA
+#[derive(serde::Deserialize, Clone)]
+pub struct SmtpSettings {
+    pub host: String,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub port: u16,
+    pub tls: SmtpTls,
+    // leave both out if the relay does not require authentication
+    pub username: Option<String>,
+    pub password: Option<Secret<String>>,
+    // if left out, we let the server pick among the ones we support
+    pub auth_mechanism: Option<SmtpAuthMechanism>,
+    // connections kept open and reused across emails
+    pub max_connections: u32,
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    // plain connection upgraded with the STARTTLS command (usually port 587)
+    Starttls,
+    // TLS from the first byte (usually port 465)
+    Implicit,
+    // no encryption at all: only for local relays and tests!
+    None,
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpAuthMechanism {
+    Plain,
+    Login,
+}
+
+fn deserialize_base_url<'de, D>(deserializer: D) -> Result<Url, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let raw = <String as serde::Deserialize>::deserialize(deserializer)?;
+    parse_base_url(&raw).map_err(serde::de::Error::custom)
+}
+
+fn parse_base_url(raw: &str) -> Result<Url, String> {
+    let invalid = |reason: String| {
+        format!(
+            "'{}' is not a valid email client base URL: {}. \
+            Use an absolute http(s) URL, e.g. 'https://api.postmarkapp.com'.",
+            raw, reason
+        )
+    };
+    let url = Url::parse(raw).map_err(|e| invalid(e.to_string()))?;
+    // 'localhost:8080' parses fine, with 'localhost' as its scheme!
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(invalid(format!("unsupported scheme '{}'", url.scheme())));
+    }
+    if url.host().is_none() {
+        return Err(invalid("missing host".into()));
+    }
+    Ok(url)
 }
 
 impl EmailClientSettings {
+    pub fn client(self) -> Arc<dyn EmailSender> {
+        if self.fallback_providers.is_empty() {
+            return self.provider_client(&self.provider);
+        }
+        let providers = std::iter::once(&self.provider)
+            .chain(&self.fallback_providers)
+            .map(|provider| (provider.to_string(), self.provider_client(provider)))
+            .collect();
+        Arc::new(FailoverEmailClient::new(
+            providers,
+            self.failover.failure_threshold,
+            std::time::Duration::from_secs(self.failover.probe_interval_seconds),
+        ))
+    }
+
+    fn provider_client(&self, provider: &EmailProvider) -> Arc<dyn EmailSender> {
+        let sender_email = self.sender().expect("Invalid sender email address.");
+        let timeout = self.timeout();
+        match provider {
+            EmailProvider::Postmark => Arc::new(PostmarkEmailClient::new(
+                self.base_url.clone(),
+                sender_email,
+                self.authorization_token.clone(),
+                timeout,
+                match &self.rate_limit {
+                    Some(limit) => Throttle::new(limit.requests_per_second, limit.max_in_flight),
+                    None => Throttle::unlimited(),
+                },
+            )),
+            EmailProvider::Smtp => Arc::new(
+                SmtpEmailClient::new(
+                    self.smtp.clone().expect("Missing 'email_client.smtp' settings."),
+                    sender_email,
+                    timeout,
+                )
+                .expect("Invalid SMTP settings."),
+            ),
+            EmailProvider::DevMailbox => Arc::new(DevMailbox::new(
+                self.dev_mailbox_directory()
+                    .expect("Missing 'email_client.dev_mailbox' settings."),
+                sender_email,
+            )),
+        }
+    }
+
+    // 'None' unless the dev mailbox is the configured provider:
+    // 'GET /dev/mailbox' must not exist anywhere else.
+    pub fn dev_mailbox_directory(&self) -> Option<std::path::PathBuf> {
+        let uses_dev_mailbox = std::iter::once(&self.provider)
+            .chain(&self.fallback_providers)
+            .any(|provider| *provider == EmailProvider::DevMailbox);
+        match &self.dev_mailbox {
+            Some(settings) if uses_dev_mailbox => Some(settings.directory.clone().into()),
+            _ => None,
+        }
+    }
+
     pub fn sender(&self) -> Result<SubscriberEmail, String> {
         SubscriberEmail::parse(self.sender_email.clone())
     }
@@ -49,6 +292,35 @@ impl EmailClientSettings {
     }
 }
 
+// how the delivery worker retries failed emails
+#[derive(serde::Deserialize, Clone)]
+pub struct IssueDeliverySettings {
+    // total number of attempts, the first one included
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_attempts: i16,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub base_delay_milliseconds: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_delay_milliseconds: u64,
+    // random extra delay, so that retries from a big batch don't all fire at once
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub jitter_milliseconds: u64,
+    // deliveries sent in a single batch (at most 500, Postmark's limit)
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub batch_size: usize,
+}
+
+impl IssueDeliverySettings {
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.max_attempts,
+            base_delay: std::time::Duration::from_millis(self.base_delay_milliseconds),
+            max_delay: std::time::Duration::from_millis(self.max_delay_milliseconds),
+            jitter: std::time::Duration::from_millis(self.jitter_milliseconds),
+        }
+    }
+}
+
 // add connection string method to the database settings struct
 
 impl DatabaseSettings {
@@ -143,4 +415,29 @@ impl TryFrom<String> for Environment {
             )),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_base_url;
+    use claim::{assert_err, assert_ok};
+
+    #[test]
+    fn absolute_http_urls_are_valid_base_urls() {
+        for url in [
+            "https://api.postmarkapp.com",
+            "http://127.0.0.1:8080",
+            "https://proxy.internal/postmark/",
+        ] {
+            assert_ok!(parse_base_url(url));
+        }
+    }
+
+    #[test]
+    fn invalid_base_urls_are_rejected() {
+        for url in ["localhost", "localhost:8080", "api.postmarkapp.com", "ftp://example.com", ""] {
+            let e = assert_err!(parse_base_url(url));
+            assert!(e.contains(&format!("'{}'", url)), "{}", e);
+        }
+    }
+}
@@ -1,7 +1,9 @@
 mod subscriber_name;
 mod subscriber_email;
 mod new_subscriber;
+mod subscriber_timezone;
 
 pub use subscriber_name::SubscriberName;
 pub use new_subscriber::NewSubscriber;
-pub use subscriber_email::SubscriberEmail;
\ No newline at end of file
+pub use subscriber_email::SubscriberEmail;
+pub use subscriber_timezone::SubscriberTimezone;
\ No newline at end of file
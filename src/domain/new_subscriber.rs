@@ -1,7 +1,11 @@
 use crate::domain::SubscriberName;
 use crate::domain::SubscriberEmail;
+use crate::domain::SubscriberTimezone;
 
 pub struct NewSubscriber {
     pub email: SubscriberEmail,
     pub name: SubscriberName,
+    // optional: issues scheduled in the subscriber's timezone
+    // fall back to UTC
+    pub timezone: Option<SubscriberTimezone>,
 }
\ No newline at end of file
@@ -0,0 +1,42 @@
+use chrono_tz::Tz;
+
+// An IANA timezone name, e.g. 'Europe/Paris'.
+// Postgres knows the same names: we hand it over as is
+// when we work out when to deliver a scheduled issue.
+#[derive(Debug)]
+pub struct SubscriberTimezone(String);
+
+impl SubscriberTimezone {
+    pub fn parse(s: String) -> Result<SubscriberTimezone, String> {
+        match s.trim().parse::<Tz>() {
+            Ok(tz) => Ok(Self(tz.name().to_string())),
+            Err(_) => Err(format!("{} is not a valid timezone.", s)),
+        }
+    }
+}
+
+impl AsRef<str> for SubscriberTimezone {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriberTimezone;
+    use claim::{assert_err, assert_ok};
+
+    #[test]
+    fn iana_timezones_are_valid() {
+        for tz in ["Europe/Paris", "America/New_York", "UTC", "Asia/Kolkata"] {
+            assert_ok!(SubscriberTimezone::parse(tz.to_string()));
+        }
+    }
+
+    #[test]
+    fn offsets_and_unknown_names_are_rejected() {
+        for tz in ["", "+02:00", "CEST", "Mars/Olympus_Mons"] {
+            assert_err!(SubscriberTimezone::parse(tz.to_string()));
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use crate::domain::SubscriberEmail;
+use crate::email_client::mime::build_message;
+use crate::email_client::{EmailError, EmailMessage, EmailSender};
+use crate::telemetry::spawn_blocking_with_tracing;
+use anyhow::Context;
+use chrono::Utc;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+const INDEX_FILE: &str = "index.json";
+
+// The API and the delivery worker each build their own client:
+// they must not update the index at the same time.
+static INDEX_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+// For local development: nothing leaves the machine.
+// Every message is written as an '.eml' file in 'directory' and listed
+// in 'index.json', which 'GET /dev/mailbox' renders.
+pub struct DevMailbox {
+    sender: SubscriberEmail,
+    directory: PathBuf,
+}
+
+// An entry of 'index.json'
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct MailboxEntry {
+    pub id: Uuid,
+    // the '.eml' file, relative to the mailbox directory
+    pub file: String,
+    pub to: String,
+    pub subject: String,
+    // RFC 3339
+    pub sent_at: String,
+    // the links found in the plain text body, e.g. the confirmation link
+    pub links: Vec<String>,
+}
+
+impl DevMailbox {
+    pub fn new(directory: PathBuf, sender: SubscriberEmail) -> Self {
+        Self { sender, directory }
+    }
+
+    async fn store(&self, message: &EmailMessage) -> Result<(), anyhow::Error> {
+        let id = Uuid::new_v4();
+        let entry = MailboxEntry {
+            id,
+            file: format!("{}.eml", id),
+            to: message.recipient().as_ref().to_owned(),
+            subject: message.subject().to_owned(),
+            sent_at: Utc::now().to_rfc3339(),
+            links: find_links(message.text_body()),
+        };
+        let message = build_message(&self.sender, message)?;
+        tracing::info!(
+            to = %entry.to,
+            subject = %entry.subject,
+            links = ?entry.links,
+            "Email stored in the dev mailbox",
+        );
+        let directory = self.directory.clone();
+        spawn_blocking_with_tracing(move || -> Result<(), anyhow::Error> {
+            std::fs::create_dir_all(&directory)
+                .context("Failed to create the dev mailbox directory.")?;
+            std::fs::write(directory.join(&entry.file), message.formatted())
+                .context("Failed to write the email to the dev mailbox.")?;
+            let _guard = INDEX_LOCK.lock().unwrap();
+            let mut index = read_index(&directory)?;
+            index.push(entry);
+            let index = serde_json::to_vec_pretty(&index)?;
+            std::fs::write(directory.join(INDEX_FILE), index)
+                .context("Failed to write the dev mailbox index.")?;
+            Ok(())
+        })
+        .await??;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailSender for DevMailbox {
+    async fn send(&self, message: &EmailMessage) -> Result<(), EmailError> {
+        self.store(message).await?;
+        Ok(())
+    }
+}
+
+// Oldest first. An empty mailbox has no index yet.
+pub fn read_index(directory: &Path) -> Result<Vec<MailboxEntry>, anyhow::Error> {
+    let index = match std::fs::read(directory.join(INDEX_FILE)) {
+        Ok(index) => index,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context("Failed to read the dev mailbox index."),
+    };
+    serde_json::from_slice(&index).context("Failed to parse the dev mailbox index.")
+}
+
+fn find_links(text: &str) -> Vec<String> {
+    text.split_whitespace()
+        .filter(|word| word.starts_with("http://") || word.starts_with("https://"))
+        .map(|link| link.trim_end_matches(['.', ',', ')', '>']).to_owned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_links, read_index, DevMailbox};
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::EmailSender;
+    use claim::assert_ok;
+
+    fn email(s: &str) -> SubscriberEmail {
+        SubscriberEmail::parse(s.into()).unwrap()
+    }
+
+    #[test]
+    fn links_are_extracted_from_the_plain_text_body() {
+        let text = "Welcome!\nVisit http://127.0.0.1/confirm?token=abc. Or https://example.com";
+        assert_eq!(
+            find_links(text),
+            vec!["http://127.0.0.1/confirm?token=abc", "https://example.com"]
+        );
+    }
+
+    #[test]
+    fn a_missing_index_is_an_empty_mailbox() {
+        let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        assert!(assert_ok!(read_index(&directory)).is_empty());
+    }
+
+    #[tokio::test]
+    async fn each_email_is_written_as_an_eml_file_and_indexed() {
+        // Arrange
+        let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let mailbox = DevMailbox::new(directory.clone(), email("newsletter@example.com"));
+
+        // Act
+        for subject in ["First", "Second"] {
+            assert_ok!(
+                mailbox
+                    .send_email(
+                        email("ursula@example.com"),
+                        subject,
+                        "<p>Hi</p>",
+                        "Visit http://127.0.0.1/confirm to confirm.",
+                    )
+                    .await
+            );
+        }
+
+        // Assert
+        let index = read_index(&directory).unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!(index[0].subject, "First");
+        assert_eq!(index[1].subject, "Second");
+        assert_eq!(index[0].to, "ursula@example.com");
+        assert_eq!(index[0].links, vec!["http://127.0.0.1/confirm"]);
+        let eml = std::fs::read_to_string(directory.join(&index[0].file)).unwrap();
+        assert!(eml.contains("Subject: First"));
+        assert!(eml.contains("Content-Type: multipart/alternative"));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,79 @@
+use crate::utils::error_chain_fmt;
+use reqwest::StatusCode;
+
+// Why an email was not sent.
+// Each backend translates its own errors, so that the delivery worker
+// (and our logs) can act on the actual reason.
+#[derive(thiserror::Error)]
+pub enum EmailError {
+    // We could not even talk to the provider, e.g. the connection was
+    // refused: it never saw the email.
+    #[error("Failed to connect to the email provider.")]
+    NotSent(#[source] anyhow::Error),
+    // We never got an answer, e.g. a timeout: the email may or may not
+    // have gone through.
+    #[error("Failed to reach the email provider.")]
+    Unreachable(#[source] anyhow::Error),
+    // The provider answered, and refused the email
+    #[error("The email provider rejected the email: {message}")]
+    Rejected {
+        kind: EmailErrorKind,
+        // 'None' if the email did not get an HTTP response of its own,
+        // e.g. a single message of a batch, or SMTP
+        status: Option<StatusCode>,
+        // Postmark's 'ErrorCode', or the SMTP reply code
+        error_code: Option<i64>,
+        message: String,
+    },
+    // Everything on our side: building the message, writing it to disk...
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmailErrorKind {
+    // Worth another try later: timeouts, rate limiting, outages...
+    Transient,
+    // It will fail again, whoever the recipient: e.g. an invalid API token
+    Permanent,
+    // This recipient will never get our emails, e.g. it bounced before
+    Recipient,
+}
+
+impl EmailError {
+    pub fn kind(&self) -> EmailErrorKind {
+        match self {
+            EmailError::Rejected { kind, .. } => *kind,
+            // All Transient: without an answer from the provider
+            // nothing says the email itself is at fault, and an outage,
+            // a timeout or a full disk may well be gone by the next attempt.
+            EmailError::NotSent(_)
+            | EmailError::Unreachable(_)
+            | EmailError::UnexpectedError(_) => EmailErrorKind::Transient,
+        }
+    }
+
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            EmailError::Rejected { status, .. } => *status,
+            _ => None,
+        }
+    }
+
+    pub fn error_code(&self) -> Option<i64> {
+        match self {
+            EmailError::Rejected { error_code, .. } => *error_code,
+            _ => None,
+        }
+    }
+
+    pub fn is_transient(&self) -> bool {
+        self.kind() == EmailErrorKind::Transient
+    }
+}
+
+impl std::fmt::Debug for EmailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
@@ -0,0 +1,380 @@
+use crate::email_client::{BatchFailure, EmailError, EmailErrorKind, EmailMessage, EmailSender};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Sends through the first healthy provider of an ordered list.
+// Each provider has its own circuit breaker: after 'failure_threshold'
+// consecutive transient failures we stop using it and move on to the next one.
+// Once every 'probe_interval' an email is sent through it again: if it goes
+// through, the provider is healthy again and we switch back to it.
+// An email the provider did not take (it refused it, or we could not connect)
+// is retried right away on the next provider: callers only see a failure once
+// all of them were tried. Timeouts are not: the email may have gone through,
+// and sending it again could deliver it twice.
+pub struct FailoverEmailClient {
+    providers: Vec<Provider>,
+    failure_threshold: u32,
+    probe_interval: Duration,
+}
+
+struct Provider {
+    // for our logs, e.g. "postmark"
+    name: String,
+    sender: Arc<dyn EmailSender>,
+    breaker: Mutex<CircuitBreaker>,
+}
+
+#[derive(Default)]
+struct CircuitBreaker {
+    consecutive_failures: u32,
+    // 'Some' while the circuit is open: when we last let an email through
+    last_attempt: Option<Instant>,
+}
+
+impl FailoverEmailClient {
+    pub fn new(
+        providers: Vec<(String, Arc<dyn EmailSender>)>,
+        failure_threshold: u32,
+        probe_interval: Duration,
+    ) -> Self {
+        Self {
+            providers: providers
+                .into_iter()
+                .map(|(name, sender)| Provider {
+                    name,
+                    sender,
+                    breaker: Mutex::new(CircuitBreaker::default()),
+                })
+                .collect(),
+            failure_threshold: failure_threshold.max(1),
+            probe_interval,
+        }
+    }
+
+    // The first provider, from 'from' on, whose circuit is closed
+    // or due for a probe. Returned with its position in the list.
+    fn pick(&self, from: usize) -> Option<(usize, &Provider)> {
+        let now = Instant::now();
+        self.providers.iter().enumerate().skip(from).find(|(_, provider)| {
+            let mut breaker = provider.breaker.lock().unwrap();
+            match breaker.last_attempt {
+                None => true,
+                Some(last_attempt) if now.duration_since(last_attempt) >= self.probe_interval => {
+                    // Only one probe per interval
+                    breaker.last_attempt = Some(now);
+                    tracing::info!(provider = %provider.name, "Probing an email provider.");
+                    true
+                }
+                Some(_) => false,
+            }
+        })
+    }
+
+    fn record<T>(&self, provider: &Provider, outcome: &Result<T, EmailError>) {
+        let mut breaker = provider.breaker.lock().unwrap();
+        match outcome {
+            Err(e) if e.is_transient() => {
+                breaker.consecutive_failures += 1;
+                if breaker.consecutive_failures >= self.failure_threshold
+                    && breaker.last_attempt.is_none()
+                {
+                    tracing::warn!(
+                        provider = %provider.name,
+                        consecutive_failures = breaker.consecutive_failures,
+                        "Email provider is failing. Failing over to the next one.",
+                    );
+                    breaker.last_attempt = Some(Instant::now());
+                }
+            }
+            // The provider answered: it is up, whatever it thought of the email
+            _ => {
+                if breaker.last_attempt.is_some() {
+                    tracing::info!(
+                        provider = %provider.name,
+                        "Email provider has recovered. Switching back to it.",
+                    );
+                }
+                *breaker = CircuitBreaker::default();
+            }
+        }
+    }
+
+    async fn send_with<'a, T, F, Fut>(&'a self, send: F) -> Result<T, EmailError>
+    where
+        F: Fn(&'a dyn EmailSender) -> Fut,
+        Fut: Future<Output = Result<T, EmailError>>,
+    {
+        let mut from = 0;
+        let mut last_error = None;
+        while let Some((index, provider)) = self.pick(from) {
+            let outcome = send(provider.sender.as_ref()).await;
+            self.record(provider, &outcome);
+            match outcome {
+                // Nothing was sent: the next provider may have better luck
+                Err(e) if was_not_sent(&e) => {
+                    tracing::warn!(
+                        provider = %provider.name,
+                        error.cause_chain = ?e,
+                        "Failed to send an email. Trying the next provider.",
+                    );
+                    last_error = Some(e);
+                    from = index + 1;
+                }
+                outcome => return outcome,
+            }
+        }
+        let message = "All the email providers are failing.";
+        Err(EmailError::Unreachable(match last_error {
+            Some(e) => anyhow::Error::new(e).context(message),
+            None => anyhow::anyhow!(message),
+        }))
+    }
+}
+
+// Errors that leave no doubt the provider did not take the email
+fn was_not_sent(e: &EmailError) -> bool {
+    match e {
+        EmailError::NotSent(_) => true,
+        // e.g. a 5xx from Postmark, or a 4xx reply from an SMTP relay
+        EmailError::Rejected { kind, .. } => *kind == EmailErrorKind::Transient,
+        EmailError::Unreachable(_) | EmailError::UnexpectedError(_) => false,
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailSender for FailoverEmailClient {
+    async fn send(&self, message: &EmailMessage) -> Result<(), EmailError> {
+        self.send_with(|sender| sender.send(message)).await
+    }
+
+    async fn send_batch(&self, messages: &[EmailMessage]) -> Result<Vec<BatchFailure>, EmailError> {
+        self.send_with(|sender| sender.send_batch(messages)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FailoverEmailClient;
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{EmailError, EmailErrorKind, EmailMessage, EmailSender};
+    use claim::{assert_err, assert_ok};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    // Succeeds or fails on demand, and counts the emails it was asked to send
+    struct StubSender {
+        calls: AtomicUsize,
+        // the subjects of the emails that went through
+        delivered: Mutex<Vec<String>>,
+        healthy: AtomicBool,
+        // the error returned while unhealthy
+        kind: EmailErrorKind,
+        // unhealthy means no answer at all, rather than a rejection
+        times_out: bool,
+    }
+
+    impl StubSender {
+        fn new(healthy: bool, kind: EmailErrorKind) -> Arc<Self> {
+            Arc::new(Self {
+                calls: AtomicUsize::new(0),
+                delivered: Mutex::new(Vec::new()),
+                healthy: AtomicBool::new(healthy),
+                kind,
+                times_out: false,
+            })
+        }
+
+        fn timing_out() -> Arc<Self> {
+            Arc::new(Self {
+                calls: AtomicUsize::new(0),
+                delivered: Mutex::new(Vec::new()),
+                healthy: AtomicBool::new(false),
+                kind: EmailErrorKind::Transient,
+                times_out: true,
+            })
+        }
+
+        fn calls(&self) -> usize {
+            self.calls.load(Ordering::SeqCst)
+        }
+
+        fn delivered(&self) -> Vec<String> {
+            self.delivered.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl EmailSender for StubSender {
+        async fn send(&self, message: &EmailMessage) -> Result<(), EmailError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if self.healthy.load(Ordering::SeqCst) {
+                self.delivered.lock().unwrap().push(message.subject().into());
+                Ok(())
+            } else if self.times_out {
+                Err(EmailError::Unreachable(anyhow::anyhow!("Timed out")))
+            } else {
+                Err(EmailError::Rejected {
+                    kind: self.kind,
+                    status: None,
+                    error_code: None,
+                    message: "Nope".into(),
+                })
+            }
+        }
+    }
+
+    fn failover(
+        primary: &Arc<StubSender>,
+        secondary: &Arc<StubSender>,
+        probe_interval: Duration,
+    ) -> FailoverEmailClient {
+        FailoverEmailClient::new(
+            vec![
+                ("primary".into(), primary.clone()),
+                ("secondary".into(), secondary.clone()),
+            ],
+            3,
+            probe_interval,
+        )
+    }
+
+    async fn send(client: &FailoverEmailClient) -> Result<(), EmailError> {
+        send_with_subject(client, "Subject").await
+    }
+
+    async fn send_with_subject(
+        client: &FailoverEmailClient,
+        subject: &str,
+    ) -> Result<(), EmailError> {
+        let recipient = SubscriberEmail::parse("ursula@example.com".into()).unwrap();
+        client.send_email(recipient, subject, "<p>Hi</p>", "Hi").await
+    }
+
+    #[tokio::test]
+    async fn fails_over_after_consecutive_transient_failures() {
+        // Arrange
+        let primary = StubSender::new(false, EmailErrorKind::Transient);
+        let secondary = StubSender::new(true, EmailErrorKind::Transient);
+        let client = failover(&primary, &secondary, Duration::from_secs(60));
+
+        // Act
+        // The emails that trip the circuit are retried on the secondary
+        for _ in 0..3 {
+            assert_ok!(send(&client).await);
+        }
+        assert_ok!(send(&client).await);
+
+        // Assert
+        assert_eq!(primary.calls(), 3);
+        assert_eq!(secondary.calls(), 3 + 1);
+    }
+
+    #[tokio::test]
+    async fn permanent_failures_do_not_trip_the_circuit() {
+        // Arrange
+        let primary = StubSender::new(false, EmailErrorKind::Permanent);
+        let secondary = StubSender::new(true, EmailErrorKind::Transient);
+        let client = failover(&primary, &secondary, Duration::from_secs(60));
+
+        // Act
+        for _ in 0..5 {
+            assert_err!(send(&client).await);
+        }
+
+        // Assert
+        assert_eq!(primary.calls(), 5);
+        assert_eq!(secondary.calls(), 0);
+    }
+
+    #[tokio::test]
+    async fn switches_back_once_a_probe_to_the_primary_succeeds() {
+        // Arrange
+        let primary = StubSender::new(false, EmailErrorKind::Transient);
+        let secondary = StubSender::new(true, EmailErrorKind::Transient);
+        let client = failover(&primary, &secondary, Duration::from_millis(100));
+        for _ in 0..3 {
+            assert_ok!(send(&client).await);
+        }
+
+        // Act - Part 1 - The primary is still down when we probe it
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_ok!(send(&client).await);
+        assert_ok!(send(&client).await);
+
+        // Act - Part 2 - It recovers
+        primary.healthy.store(true, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        for _ in 0..3 {
+            assert_ok!(send(&client).await);
+        }
+
+        // Assert
+        assert_eq!(primary.calls(), 3 + 1 + 3);
+        assert_eq!(secondary.calls(), 3 + 1 + 1);
+    }
+
+    #[tokio::test]
+    async fn a_failed_probe_is_retried_on_the_next_provider() {
+        // Arrange
+        let primary = StubSender::new(false, EmailErrorKind::Transient);
+        let secondary = StubSender::new(true, EmailErrorKind::Transient);
+        let client = failover(&primary, &secondary, Duration::from_millis(100));
+        for _ in 0..3 {
+            assert_ok!(send(&client).await);
+        }
+        tokio::time::sleep(Duration::from_millis(150)).await;
+
+        // Act
+        let outcome = send_with_subject(&client, "Probe").await;
+
+        // Assert
+        assert_ok!(outcome);
+        // It went to the primary first, which is still down
+        assert_eq!(primary.calls(), 3 + 1);
+        assert_eq!(secondary.delivered().last().unwrap(), "Probe");
+    }
+
+    #[tokio::test]
+    async fn emails_that_may_have_been_sent_are_not_sent_again() {
+        // Arrange
+        let primary = StubSender::timing_out();
+        let secondary = StubSender::new(true, EmailErrorKind::Transient);
+        let client = failover(&primary, &secondary, Duration::from_secs(60));
+
+        // Act
+        // A timeout trips the circuit, but the email is not retried elsewhere
+        for _ in 0..3 {
+            let e = assert_err!(send(&client).await);
+            assert!(matches!(e, EmailError::Unreachable(_)));
+        }
+        assert_ok!(send(&client).await);
+
+        // Assert
+        assert_eq!(primary.calls(), 3);
+        assert_eq!(secondary.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn fails_if_every_provider_is_failing() {
+        // Arrange
+        let primary = StubSender::new(false, EmailErrorKind::Transient);
+        let secondary = StubSender::new(false, EmailErrorKind::Transient);
+        let client = failover(&primary, &secondary, Duration::from_secs(60));
+        // Each email is tried on both
+        for _ in 0..3 {
+            let e = assert_err!(send(&client).await);
+            assert!(matches!(e, EmailError::Unreachable(_)));
+        }
+
+        // Act
+        let outcome = send(&client).await;
+
+        // Assert
+        let e = assert_err!(outcome);
+        assert!(e.is_transient());
+        assert_eq!(primary.calls(), 3);
+        assert_eq!(secondary.calls(), 3);
+    }
+}
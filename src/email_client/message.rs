@@ -0,0 +1,131 @@
+use crate::domain::SubscriberEmail;
+use std::collections::BTreeMap;
+
+// Everything we can put in an email.
+// Built with 'EmailMessage::new' and the chainable setters below, e.g.
+//
+//   EmailMessage::new(recipient, "Issue #1", html, text)
+//       .reply_to(editor)
+//       .tag("newsletter")
+//       .attachment("issue-1.pdf", "application/pdf", pdf_bytes)
+//
+// Each backend renders it in its own format: see 'postmark' and 'mime'.
+#[derive(Debug, Clone)]
+pub struct EmailMessage {
+    pub(crate) to: SubscriberEmail,
+    pub(crate) subject: String,
+    pub(crate) html_body: String,
+    pub(crate) text_body: String,
+    pub(crate) reply_to: Option<SubscriberEmail>,
+    pub(crate) cc: Vec<SubscriberEmail>,
+    pub(crate) bcc: Vec<SubscriberEmail>,
+    pub(crate) headers: Vec<(String, String)>,
+    // Provider-side bookkeeping: Postmark groups its statistics by tag,
+    // and hands metadata back to us in its webhooks.
+    pub(crate) tag: Option<String>,
+    pub(crate) metadata: BTreeMap<String, String>,
+    // Postmark keeps transactional and broadcast emails apart
+    pub(crate) message_stream: Option<String>,
+    pub(crate) attachments: Vec<Attachment>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    pub(crate) name: String,
+    // e.g. "application/pdf"
+    pub(crate) content_type: String,
+    // raw bytes: each backend encodes them as it needs to
+    pub(crate) content: Vec<u8>,
+}
+
+impl EmailMessage {
+    pub fn new(
+        to: SubscriberEmail,
+        subject: impl Into<String>,
+        html_body: impl Into<String>,
+        text_body: impl Into<String>,
+    ) -> Self {
+        Self {
+            to,
+            subject: subject.into(),
+            html_body: html_body.into(),
+            text_body: text_body.into(),
+            reply_to: None,
+            cc: Vec::new(),
+            bcc: Vec::new(),
+            headers: Vec::new(),
+            tag: None,
+            metadata: BTreeMap::new(),
+            message_stream: None,
+            attachments: Vec::new(),
+        }
+    }
+
+    pub fn reply_to(mut self, reply_to: SubscriberEmail) -> Self {
+        self.reply_to = Some(reply_to);
+        self
+    }
+
+    pub fn cc(mut self, cc: SubscriberEmail) -> Self {
+        self.cc.push(cc);
+        self
+    }
+
+    pub fn bcc(mut self, bcc: SubscriberEmail) -> Self {
+        self.bcc.push(bcc);
+        self
+    }
+
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    // Newsletters must carry the RFC 8058 one-click unsubscribe headers:
+    // Gmail and Yahoo reject bulk mail that does not.
+    pub fn list_unsubscribe(self, unsubscribe_link: &str) -> Self {
+        self.header("List-Unsubscribe", format!("<{}>", unsubscribe_link))
+            .header("List-Unsubscribe-Post", "List-Unsubscribe=One-Click")
+    }
+
+    pub fn tag(mut self, tag: impl Into<String>) -> Self {
+        self.tag = Some(tag.into());
+        self
+    }
+
+    pub fn metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn message_stream(mut self, message_stream: impl Into<String>) -> Self {
+        self.message_stream = Some(message_stream.into());
+        self
+    }
+
+    pub fn attachment(
+        mut self,
+        name: impl Into<String>,
+        content_type: impl Into<String>,
+        content: Vec<u8>,
+    ) -> Self {
+        self.attachments.push(Attachment {
+            name: name.into(),
+            content_type: content_type.into(),
+            content,
+        });
+        self
+    }
+
+    pub fn recipient(&self) -> &SubscriberEmail {
+        &self.to
+    }
+
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    pub fn text_body(&self) -> &str {
+        &self.text_body
+    }
+}
@@ -0,0 +1,139 @@
+use crate::domain::SubscriberEmail;
+use crate::email_client::EmailMessage;
+use lettre::message::header::{ContentType, HeaderName, HeaderValue};
+use lettre::message::{Attachment, Mailbox, MultiPart};
+use lettre::Message;
+
+// Render an email as a MIME message, for the backends that hand raw
+// messages over (SMTP, the dev mailbox) rather than calling an HTTP API.
+//
+// The html and plain text bodies go in a multipart/alternative:
+// mail clients pick the last part they can display.
+// Attachments, if any, wrap it in a multipart/mixed.
+pub fn build_message(sender: &SubscriberEmail, message: &EmailMessage) -> Result<Message, anyhow::Error> {
+    let mailbox = |email: &SubscriberEmail| email.as_ref().parse::<Mailbox>();
+    let mut builder = Message::builder()
+        .from(mailbox(sender)?)
+        .to(mailbox(&message.to)?)
+        .subject(&message.subject);
+    if let Some(reply_to) = &message.reply_to {
+        builder = builder.reply_to(mailbox(reply_to)?);
+    }
+    for cc in &message.cc {
+        builder = builder.cc(mailbox(cc)?);
+    }
+    // Only in the envelope: lettre leaves the 'Bcc' header out
+    for bcc in &message.bcc {
+        builder = builder.bcc(mailbox(bcc)?);
+    }
+    for (name, value) in &message.headers {
+        builder = builder.raw_header(header(name, value)?);
+    }
+    // Postmark's SMTP service reads tags, metadata and streams from these
+    // headers; other relays pass them along untouched.
+    if let Some(tag) = &message.tag {
+        builder = builder.raw_header(header("X-PM-Tag", tag)?);
+    }
+    for (key, value) in &message.metadata {
+        builder = builder.raw_header(header(&format!("X-PM-Metadata-{}", key), value)?);
+    }
+    if let Some(message_stream) = &message.message_stream {
+        builder = builder.raw_header(header("X-PM-Message-Stream", message_stream)?);
+    }
+    let body = MultiPart::alternative_plain_html(
+        message.text_body.clone(),
+        message.html_body.clone(),
+    );
+    let message = if message.attachments.is_empty() {
+        builder.multipart(body)?
+    } else {
+        let mut mixed = MultiPart::mixed().multipart(body);
+        for attachment in &message.attachments {
+            mixed = mixed.singlepart(Attachment::new(attachment.name.clone()).body(
+                attachment.content.clone(),
+                ContentType::parse(&attachment.content_type)?,
+            ));
+        }
+        builder.multipart(mixed)?
+    };
+    Ok(message)
+}
+
+fn header(name: &str, value: &str) -> Result<HeaderValue, anyhow::Error> {
+    let name = HeaderName::new_from_ascii(name.to_owned())
+        .map_err(|_| anyhow::anyhow!("{} is not a valid header name.", name))?;
+    Ok(HeaderValue::new(name, value.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::build_message;
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::EmailMessage;
+    use claim::assert_err;
+
+    fn email(s: &str) -> SubscriberEmail {
+        SubscriberEmail::parse(s.into()).unwrap()
+    }
+
+    fn render(message: &EmailMessage) -> String {
+        let message = build_message(&email("newsletter@example.com"), message).unwrap();
+        String::from_utf8(message.formatted()).unwrap()
+    }
+
+    #[test]
+    fn every_field_of_the_message_is_rendered() {
+        // Arrange
+        let message = EmailMessage::new(email("ursula@example.com"), "Issue #1", "<p>Hi</p>", "Hi")
+            .reply_to(email("editor@example.com"))
+            .cc(email("cc@example.com"))
+            .bcc(email("bcc@example.com"))
+            .header("X-Campaign", "autumn")
+            .tag("newsletter")
+            .metadata("issue", "1")
+            .message_stream("broadcast");
+
+        // Act
+        let rendered = render(&message);
+
+        // Assert
+        assert!(rendered.contains("Reply-To: editor@example.com"));
+        assert!(rendered.contains("Cc: cc@example.com"));
+        assert!(!rendered.contains("bcc@example.com"));
+        assert!(rendered.contains("X-Campaign: autumn"));
+        assert!(rendered.contains("X-PM-Tag: newsletter"));
+        assert!(rendered.contains("X-PM-Metadata-issue: 1"));
+        assert!(rendered.contains("X-PM-Message-Stream: broadcast"));
+        assert!(rendered.contains("Content-Type: multipart/alternative"));
+        assert!(!rendered.contains("multipart/mixed"));
+    }
+
+    #[test]
+    fn attachments_go_in_a_multipart_mixed() {
+        // Arrange
+        let message = EmailMessage::new(email("ursula@example.com"), "Issue #1", "<p>Hi</p>", "Hi")
+            .attachment(
+                "issue-1.pdf",
+                "application/pdf",
+                b"%PDF-1.4\n%\xe2\xe3\xcf\xd3".to_vec(),
+            );
+
+        // Act
+        let rendered = render(&message);
+
+        // Assert
+        assert!(rendered.contains("Content-Type: multipart/mixed"));
+        assert!(rendered.contains("Content-Type: multipart/alternative"));
+        assert!(rendered.contains("Content-Type: application/pdf"));
+        assert!(rendered.contains("filename=\"issue-1.pdf\""));
+        // binary content is base64 encoded
+        assert!(rendered.contains("JVBERi0xLjQKJeLjz9M="));
+    }
+
+    #[test]
+    fn invalid_headers_are_rejected() {
+        let message = EmailMessage::new(email("ursula@example.com"), "Issue #1", "<p>Hi</p>", "Hi")
+            .header("Not a header", "value");
+        assert_err!(build_message(&email("newsletter@example.com"), &message));
+    }
+}
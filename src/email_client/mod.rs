@@ -0,0 +1,76 @@
+// Everything that sends emails on our behalf.
+// Handlers and the delivery worker only know about 'EmailSender':
+// the backend is picked in the configuration (see 'EmailClientSettings').
+mod dev_mailbox;
+mod error;
+mod failover;
+mod message;
+mod mime;
+mod postmark;
+mod smtp;
+mod throttle;
+
+pub use dev_mailbox::{read_index, DevMailbox, MailboxEntry};
+pub use error::{EmailError, EmailErrorKind};
+pub use failover::FailoverEmailClient;
+pub use message::{Attachment, EmailMessage};
+pub use postmark::PostmarkEmailClient;
+pub use smtp::SmtpEmailClient;
+pub use throttle::Throttle;
+
+use crate::domain::SubscriberEmail;
+
+#[async_trait::async_trait]
+pub trait EmailSender: Send + Sync {
+    // The only method a backend has to implement
+    async fn send(&self, message: &EmailMessage) -> Result<(), EmailError>;
+
+    async fn send_email(
+        &self,
+        recipient: SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+    ) -> Result<(), EmailError> {
+        self.send(&EmailMessage::new(recipient, subject, html_content, text_content))
+            .await
+    }
+
+    async fn send_newsletter(
+        &self,
+        recipient: SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        unsubscribe_link: &str,
+    ) -> Result<(), EmailError> {
+        let message = EmailMessage::new(recipient, subject, html_content, text_content)
+            .list_unsubscribe(unsubscribe_link);
+        self.send(&message).await
+    }
+
+    // Send up to 'MAX_BATCH_SIZE' emails at once.
+    // An 'Err' means that nothing was sent; otherwise we get back the
+    // emails that were rejected, so that only those are retried.
+    // Backends without a batch API send them one at a time.
+    async fn send_batch(&self, messages: &[EmailMessage]) -> Result<Vec<BatchFailure>, EmailError> {
+        let mut failures = Vec::new();
+        for (index, message) in messages.iter().enumerate() {
+            if let Err(error) = self.send(message).await {
+                failures.push(BatchFailure { index, error });
+            }
+        }
+        Ok(failures)
+    }
+}
+
+// Postmark's '/email/batch' endpoint accepts at most 500 messages per call.
+pub const MAX_BATCH_SIZE: usize = 500;
+
+// An email of the batch that was not sent
+#[derive(Debug)]
+pub struct BatchFailure {
+    // position of the email in the batch we were given
+    pub index: usize,
+    pub error: EmailError,
+}
@@ -0,0 +1,836 @@
+use crate::domain::SubscriberEmail;
+use crate::email_client::{
+    BatchFailure, EmailError, EmailErrorKind, EmailMessage, EmailSender, Throttle,
+    MAX_BATCH_SIZE,
+};
+use std::collections::BTreeMap;
+use anyhow::Context;
+use reqwest::StatusCode;
+use reqwest::Client;
+use reqwest::Url;
+use secrecy::{ExposeSecret, Secret};
+
+// Sends emails through Postmark's HTTP API
+pub struct PostmarkEmailClient {
+    sender: SubscriberEmail,
+    // always ends with a '/', see 'PostmarkEmailClient::new'
+    base_url: Url,
+    http_client: Client,
+    // we dont want to log this by accident
+    authorization_token: Secret<String>,
+    throttle: Throttle,
+}
+
+// How many times we wait for 'Retry-After' before giving up
+// on a request, and how long we are willing to wait each time:
+// the delivery worker's own retries take over after that.
+const MAX_THROTTLED_ATTEMPTS: u32 = 3;
+const MAX_RETRY_AFTER: std::time::Duration = std::time::Duration::from_secs(10);
+
+impl PostmarkEmailClient {
+    pub fn new(mut base_url: Url, sender: SubscriberEmail, authorization_token: Secret<String>, timeout: std::time::Duration, throttle: Throttle) -> Self{
+        let http_client = Client::builder()
+            .timeout(timeout)
+            .build()
+            .unwrap();
+        // 'Url::join' replaces the last segment of a path that does not end
+        // with a '/': 'https://proxy/postmark' joined with 'email' would give
+        // us 'https://proxy/email'.
+        if !base_url.path().ends_with('/') {
+            let path = format!("{}/", base_url.path());
+            base_url.set_path(&path);
+        }
+        Self {
+            http_client,
+            base_url,
+            sender,
+            authorization_token,
+            throttle,
+        }
+    }
+
+    // 'path' must be relative (no leading '/'): an absolute path would
+    // drop the sub-path 'base_url' may have.
+    fn endpoint(&self, path: &str) -> Result<Url, EmailError> {
+        let url = self
+            .base_url
+            .join(path)
+            .with_context(|| format!("Failed to build the URL of the '{}' endpoint.", path))?;
+        Ok(url)
+    }
+
+    async fn post_email<T: serde::Serialize + ?Sized>(
+        &self,
+        url: Url,
+        request_body: &T,
+    ) -> Result<reqwest::Response, EmailError> {
+        let mut n_throttled = 0;
+        let response = loop {
+            let _permit = self.throttle.acquire().await;
+            let response = self.http_client
+                .post(url.clone())
+                .header("X-Postmark-Server-Token",
+                    self.authorization_token.expose_secret())
+                .json(request_body)
+                .send()
+                .await
+                .map_err(|e| {
+                    if e.is_connect() {
+                        EmailError::NotSent(e.into())
+                    } else {
+                        EmailError::Unreachable(e.into())
+                    }
+                })?;
+            // Postmark throttles us: hold every request back for as long as it
+            // asks, then try again.
+            match retry_after(&response) {
+                Some(retry_after)
+                    if retry_after <= MAX_RETRY_AFTER && n_throttled < MAX_THROTTLED_ATTEMPTS =>
+                {
+                    n_throttled += 1;
+                    tracing::warn!(
+                        retry_after_ms = retry_after.as_millis() as u64,
+                        n_throttled,
+                        "Postmark is throttling us. Waiting before retrying.",
+                    );
+                    self.throttle.pause_for(retry_after);
+                }
+                _ => break response,
+            }
+        };
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        // Postmark explains what went wrong in the body, e.g.
+        // {"ErrorCode": 406, "Message": "You tried to send to a recipient that has been marked as inactive."}
+        let body = response.text().await.unwrap_or_default();
+        let (error_code, message) = match serde_json::from_str::<PostmarkResult>(&body) {
+            Ok(error) => (Some(error.error_code), error.message),
+            Err(_) => (None, body),
+        };
+        Err(EmailError::Rejected {
+            kind: classify(Some(status), error_code),
+            status: Some(status),
+            error_code,
+            message,
+        })
+    }
+}
+
+// 'Retry-After' on a 429, in seconds (we don't bother with HTTP dates)
+fn retry_after(response: &reqwest::Response) -> Option<std::time::Duration> {
+    if response.status() != StatusCode::TOO_MANY_REQUESTS {
+        return None;
+    }
+    let seconds = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(std::time::Duration::from_secs(seconds))
+}
+
+// See https://postmarkapp.com/developer/api/overview#error-codes
+fn classify(status: Option<StatusCode>, error_code: Option<i64>) -> EmailErrorKind {
+    match (status, error_code) {
+        // Inactive recipient: it bounced or marked us as spam
+        (_, Some(406)) => EmailErrorKind::Recipient,
+        // Postmark is down for maintenance
+        (_, Some(100)) => EmailErrorKind::Transient,
+        (Some(status), _) if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS => {
+            EmailErrorKind::Transient
+        }
+        // Invalid token, malformed request...
+        _ => EmailErrorKind::Permanent,
+    }
+}
+
+// The body of an error response, and of each result of a batch:
+// Postmark answers a batch with a 200 and one result per message,
+// in the same order, and each of them can fail on its own.
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PostmarkResult {
+    // 0 means the message was accepted
+    error_code: i64,
+    message: String,
+}
+
+#[async_trait::async_trait]
+impl EmailSender for PostmarkEmailClient {
+    async fn send(&self, message: &EmailMessage) -> Result<(), EmailError> {
+        let url = self.endpoint("email")?;
+
+        //allocates a bunch of new memory to store a cloned String
+        //lets try to reference the existing data
+        // make the definition for sendemailrequest use a string slice
+        // a string slice is just a pointer to a memory buffer owned by someone else
+        //to store a reference in a struct we need to add a lifetime parameter
+        // which keeps track of how long the references are valid for
+        let request_body = SendEmailRequest::new(&self.sender, message);
+        self.post_email(url, &request_body).await?;
+        Ok(())
+    }
+
+    // One HTTP call for the whole batch
+    async fn send_batch(&self, messages: &[EmailMessage]) -> Result<Vec<BatchFailure>, EmailError> {
+        if messages.len() > MAX_BATCH_SIZE {
+            return Err(anyhow::anyhow!(
+                "Postmark accepts at most {} messages per batch, we got {}.",
+                MAX_BATCH_SIZE,
+                messages.len()
+            )
+            .into());
+        }
+        if messages.is_empty() {
+            return Ok(Vec::new());
+        }
+        let url = self.endpoint("email/batch")?;
+        let request_body: Vec<SendEmailRequest> = messages
+            .iter()
+            .map(|message| SendEmailRequest::new(&self.sender, message))
+            .collect();
+        let results: Vec<PostmarkResult> = self
+            .post_email(url, &request_body)
+            .await?
+            .json()
+            .await
+            .context("Failed to parse the results of a batch.")?;
+        if results.len() != messages.len() {
+            return Err(anyhow::anyhow!(
+                "Postmark returned {} results for a batch of {} messages.",
+                results.len(),
+                messages.len()
+            )
+            .into());
+        }
+        let failures = results
+            .into_iter()
+            .enumerate()
+            .filter(|(_, result)| result.error_code != 0)
+            .map(|(index, result)| BatchFailure {
+                index,
+                error: EmailError::Rejected {
+                    kind: classify(None, Some(result.error_code)),
+                    status: None,
+                    error_code: Some(result.error_code),
+                    message: result.message,
+                },
+            })
+            .collect();
+        Ok(failures)
+    }
+}
+
+// See https://postmarkapp.com/developer/api/email-api#send-a-single-email
+// Optional fields are left out rather than sent empty.
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct SendEmailRequest<'a> {
+    from: &'a str,
+    to: &'a str,
+    subject: &'a str,
+    html_body: &'a str,
+    text_body: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_to: Option<&'a str>,
+    // comma separated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cc: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bcc: Option<String>,
+    // Postmark rejects an empty list, leave the field out instead
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    headers: Vec<EmailHeader<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<&'a BTreeMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_stream: Option<&'a str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<PostmarkAttachment<'a>>,
+}
+
+impl<'a> SendEmailRequest<'a> {
+    fn new(sender: &'a SubscriberEmail, message: &'a EmailMessage) -> Self {
+        let join = |emails: &[SubscriberEmail]| {
+            if emails.is_empty() {
+                None
+            } else {
+                Some(emails.iter().map(|e| e.as_ref()).collect::<Vec<_>>().join(","))
+            }
+        };
+        Self {
+            from: sender.as_ref(),
+            to: message.to.as_ref(),
+            subject: &message.subject,
+            html_body: &message.html_body,
+            text_body: &message.text_body,
+            reply_to: message.reply_to.as_ref().map(|e| e.as_ref()),
+            cc: join(&message.cc),
+            bcc: join(&message.bcc),
+            headers: message
+                .headers
+                .iter()
+                .map(|(name, value)| EmailHeader { name, value })
+                .collect(),
+            tag: message.tag.as_deref(),
+            metadata: Some(&message.metadata).filter(|m| !m.is_empty()),
+            message_stream: message.message_stream.as_deref(),
+            attachments: message
+                .attachments
+                .iter()
+                .map(|attachment| PostmarkAttachment {
+                    name: &attachment.name,
+                    content: base64::encode(&attachment.content),
+                    content_type: &attachment.content_type,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct EmailHeader<'a> {
+    name: &'a str,
+    value: &'a str,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct PostmarkAttachment<'a> {
+    name: &'a str,
+    // base64
+    content: String,
+    content_type: &'a str,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{
+        EmailErrorKind, EmailMessage, EmailSender, PostmarkEmailClient, Throttle,
+    };
+    use fake::faker::internet::en::SafeEmail;
+    use fake::faker::lorem::en::{Paragraph,Sentence};
+    use fake::{Fake,Faker};
+    use wiremock::matchers::{header_exists, header, path, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    use secrecy::Secret;
+    use wiremock::Request;
+    use wiremock::matchers::any;
+    use claim::assert_ok;
+    use claim::assert_err;
+    use reqwest::Url;
+
+    struct SendEmailBodyMatcher;
+
+    impl wiremock::Match for SendEmailBodyMatcher {
+        fn matches(&self, request: &Request) -> bool {
+            // try to parse the body as a json value
+            let result: Result<serde_json::Value, _> =
+                serde_json::from_slice(&request.body);
+            if let Ok(body) = result {
+                //check that all the mandatory fields are populated
+                // without inspecting the field values
+                body.get("From").is_some()
+                && body.get("To").is_some()
+                    && body.get("Subject").is_some()
+                    && body.get("HtmlBody").is_some()
+                    && body.get("TextBody").is_some()
+            } else{
+                false
+            }
+        }
+    }
+
+    struct ListUnsubscribeHeadersMatcher;
+
+    impl wiremock::Match for ListUnsubscribeHeadersMatcher {
+        fn matches(&self, request: &Request) -> bool {
+            let result: Result<serde_json::Value, _> =
+                serde_json::from_slice(&request.body);
+            let body = match result {
+                Ok(body) => body,
+                Err(_) => return false,
+            };
+            let headers = match body.get("Headers").and_then(|h| h.as_array()) {
+                Some(headers) => headers,
+                None => return false,
+            };
+            let has_header = |name: &str, value: &str| {
+                headers.iter().any(|h| h["Name"] == name && h["Value"] == value)
+            };
+            has_header("List-Unsubscribe", "<https://example.com/unsubscribe>")
+                && has_header("List-Unsubscribe-Post", "List-Unsubscribe=One-Click")
+        }
+    }
+
+    // Generate a random email subject
+    fn subject() -> String {
+        Sentence(1..2).fake()
+    }
+
+    // Generate a random email content
+    fn content() -> String {
+        Paragraph(1..10).fake()
+    }
+
+    // Generate a random subscriber email
+    fn email() -> SubscriberEmail {
+        SubscriberEmail::parse(SafeEmail().fake()).unwrap()
+    }
+
+    // get a test instance of 'PostmarkEmailClient'
+    fn email_client(base_url: String) -> PostmarkEmailClient {
+        PostmarkEmailClient::new(Url::parse(&base_url).unwrap(), email(), Secret::new(Faker.fake()), std::time::Duration::from_millis(200), Throttle::unlimited())
+    }
+
+    #[tokio::test]
+    async fn send_email_sends_the_expected_request() {
+        //Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(header_exists("X-Postmark-Server-Token"))
+                            .and(header("Content-Type", "application/json"))
+                            .and(path("/email"))
+                            .and(method("POST"))
+                            .and(SendEmailBodyMatcher)
+                            .respond_with(ResponseTemplate::new(200))
+                            .expect(1)
+                            .mount(&mock_server)
+                            .await;
+
+        // Act
+        let _ = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        //Assert
+    }
+
+    #[tokio::test]
+    async fn sub_paths_of_the_base_url_are_kept() {
+        // e.g. Postmark behind a proxy, with or without a trailing slash
+        for suffix in ["/postmark", "/postmark/"] {
+            // Arrange
+            let mock_server = MockServer::start().await;
+            let email_client = email_client(format!("{}{}", mock_server.uri(), suffix));
+
+            Mock::given(path("/postmark/email"))
+                .and(method("POST"))
+                .respond_with(ResponseTemplate::new(200))
+                .expect(1)
+                .mount(&mock_server)
+                .await;
+
+            // Act
+            let outcome = email_client
+                .send_email(email(), &subject(), &content(), &content())
+                .await;
+
+            // Assert
+            assert_ok!(outcome);
+        }
+    }
+
+    #[tokio::test]
+    async fn send_batch_keeps_the_sub_path_of_the_base_url() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(format!("{}/postmark", mock_server.uri()));
+
+        Mock::given(path("/postmark/email/batch"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+                {"ErrorCode": 0, "Message": "OK"}
+            ])))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client.send_batch(&[newsletter_email()]).await;
+
+        // Assert
+        assert!(assert_ok!(outcome).is_empty());
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_if_the_server_returns_500() {
+        //Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        //we do not copy in all the matchers we have in the other test.
+        // the purpose of this test is not to assert on the request we are sending out
+        // we add the bare minimum needed to trigger the path we want to test in 'send_email'
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+        //assert
+        let e = assert_err!(outcome);
+        assert_eq!(e.kind(), EmailErrorKind::Transient);
+        assert_eq!(e.status().map(|s| s.as_u16()), Some(500));
+    }
+
+    #[tokio::test]
+    async fn send_email_parses_postmark_error_codes() {
+        for (status, error_code, expected) in [
+            (401, 10, EmailErrorKind::Permanent),
+            (422, 300, EmailErrorKind::Permanent),
+            (422, 406, EmailErrorKind::Recipient),
+            (503, 100, EmailErrorKind::Transient),
+        ] {
+            //Arrange
+            let mock_server = MockServer::start().await;
+            let email_client = email_client(mock_server.uri());
+            let body = serde_json::json!({
+                "ErrorCode": error_code,
+                "Message": "Something went wrong",
+            });
+
+            Mock::given(any())
+                .respond_with(ResponseTemplate::new(status).set_body_json(body))
+                .expect(1)
+                .mount(&mock_server)
+                .await;
+
+            // Act
+            let outcome = email_client
+                .send_email(email(), &subject(), &content(), &content())
+                .await;
+
+            // Assert
+            let e = assert_err!(outcome);
+            assert_eq!(e.kind(), expected);
+            assert_eq!(e.status().map(|s| s.as_u16()), Some(status));
+            assert_eq!(e.error_code(), Some(error_code));
+            assert_eq!(e.to_string(), "The email provider rejected the email: Something went wrong");
+        }
+    }
+
+    #[tokio::test]
+    async fn send_email_succeeds_if_the_server_returns_200() {
+        //Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        //we do not copy in all the matchers we have in the other test.
+        // the purpose of this test is not to assert on the request we are sending out
+        // we add the bare minimum needed to trigger the path we want to test in 'send_email'
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+        //assert
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_times_out_if_the_server_takes_too_long() {
+        //Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        let response = ResponseTemplate::new(200)
+            .set_delay(std::time::Duration::from_secs(180));
+
+        Mock::given(any())
+            .respond_with(response)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        let e = assert_err!(outcome);
+        assert_eq!(e.kind(), EmailErrorKind::Transient);
+        assert!(e.status().is_none());
+    }
+
+    #[tokio::test]
+    async fn send_newsletter_attaches_the_list_unsubscribe_headers() {
+        //Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(path("/email"))
+            .and(method("POST"))
+            .and(SendEmailBodyMatcher)
+            .and(ListUnsubscribeHeadersMatcher)
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_newsletter(
+                email(),
+                &subject(),
+                &content(),
+                &content(),
+                "https://example.com/unsubscribe",
+            )
+            .await;
+
+        // Assert
+        assert_ok!(outcome);
+    }
+
+    fn newsletter_email() -> EmailMessage {
+        EmailMessage::new(email(), subject(), content(), content())
+            .list_unsubscribe("https://example.com/unsubscribe")
+    }
+
+    #[tokio::test]
+    async fn send_batch_sends_all_the_messages_in_a_single_request() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let results = serde_json::json!([
+            {"ErrorCode": 0, "Message": "OK"},
+            {"ErrorCode": 0, "Message": "OK"},
+            {"ErrorCode": 0, "Message": "OK"},
+        ]);
+
+        Mock::given(header_exists("X-Postmark-Server-Token"))
+            .and(path("/email/batch"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(results))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let emails: Vec<_> = (0..3).map(|_| newsletter_email()).collect();
+        let outcome = email_client.send_batch(&emails).await;
+
+        // Assert
+        assert!(assert_ok!(outcome).is_empty());
+        let request = &mock_server.received_requests().await.unwrap()[0];
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        let messages = body.as_array().unwrap();
+        assert_eq!(messages.len(), 3);
+        for (message, email) in messages.iter().zip(&emails) {
+            assert_eq!(message["To"], email.recipient().as_ref());
+            assert_eq!(message["Headers"][0]["Name"], "List-Unsubscribe");
+        }
+    }
+
+    #[tokio::test]
+    async fn send_batch_reports_the_messages_that_were_rejected() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let results = serde_json::json!([
+            {"ErrorCode": 0, "Message": "OK"},
+            {"ErrorCode": 406, "Message": "You tried to send to a recipient that has been marked as inactive."},
+            {"ErrorCode": 0, "Message": "OK"},
+        ]);
+
+        Mock::given(path("/email/batch"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(results))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let emails: Vec<_> = (0..3).map(|_| newsletter_email()).collect();
+        let outcome = email_client.send_batch(&emails).await;
+
+        // Assert
+        let failures = assert_ok!(outcome);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].index, 1);
+        assert_eq!(failures[0].error.error_code(), Some(406));
+        assert_eq!(failures[0].error.kind(), EmailErrorKind::Recipient);
+    }
+
+    #[tokio::test]
+    async fn send_batch_fails_if_the_server_returns_500() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client.send_batch(&[newsletter_email()]).await;
+
+        // Assert
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_batch_rejects_batches_that_are_too_large() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let emails: Vec<_> = (0..501).map(|_| newsletter_email()).collect();
+        let outcome = email_client.send_batch(&emails).await;
+
+        // Assert
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_waits_for_retry_after_when_throttled() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        let throttled_guard = Mock::given(any())
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "1"))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount_as_scoped(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let start = std::time::Instant::now();
+
+        // Act
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert_ok!(outcome);
+        assert!(start.elapsed() >= std::time::Duration::from_secs(1));
+        drop(throttled_guard);
+    }
+
+    #[tokio::test]
+    async fn send_email_gives_up_if_retry_after_is_too_long() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "3600"))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        let e = assert_err!(outcome);
+        assert_eq!(e.kind(), EmailErrorKind::Transient);
+        assert_eq!(e.status().map(|s| s.as_u16()), Some(429));
+    }
+
+    #[tokio::test]
+    async fn send_serializes_every_field_of_the_message() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(path("/email"))
+            .and(method("POST"))
+            .and(SendEmailBodyMatcher)
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let message = EmailMessage::new(email(), subject(), content(), content())
+            .reply_to(SubscriberEmail::parse("editor@example.com".into()).unwrap())
+            .cc(SubscriberEmail::parse("cc1@example.com".into()).unwrap())
+            .cc(SubscriberEmail::parse("cc2@example.com".into()).unwrap())
+            .bcc(SubscriberEmail::parse("bcc@example.com".into()).unwrap())
+            .header("X-Campaign", "autumn")
+            .tag("newsletter")
+            .metadata("issue", "1")
+            .message_stream("broadcast")
+            .attachment("issue-1.pdf", "application/pdf", b"%PDF-1.4".to_vec());
+
+        // Act
+        let outcome = email_client.send(&message).await;
+
+        // Assert
+        assert_ok!(outcome);
+        let request = &mock_server.received_requests().await.unwrap()[0];
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(body["ReplyTo"], "editor@example.com");
+        assert_eq!(body["Cc"], "cc1@example.com,cc2@example.com");
+        assert_eq!(body["Bcc"], "bcc@example.com");
+        assert_eq!(body["Headers"][0]["Name"], "X-Campaign");
+        assert_eq!(body["Headers"][0]["Value"], "autumn");
+        assert_eq!(body["Tag"], "newsletter");
+        assert_eq!(body["Metadata"]["issue"], "1");
+        assert_eq!(body["MessageStream"], "broadcast");
+        assert_eq!(body["Attachments"][0]["Name"], "issue-1.pdf");
+        assert_eq!(body["Attachments"][0]["ContentType"], "application/pdf");
+        assert_eq!(body["Attachments"][0]["Content"], "JVBERi0xLjQ=");
+    }
+
+    #[tokio::test]
+    async fn optional_fields_are_left_out_when_empty() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert_ok!(outcome);
+        let request = &mock_server.received_requests().await.unwrap()[0];
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        for field in ["ReplyTo", "Cc", "Bcc", "Headers", "Tag", "Metadata", "MessageStream", "Attachments"] {
+            assert!(body.get(field).is_none(), "{} should be left out", field);
+        }
+    }
+}
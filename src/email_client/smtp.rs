@@ -0,0 +1,339 @@
+use crate::configuration::{SmtpAuthMechanism, SmtpSettings, SmtpTls};
+use crate::domain::SubscriberEmail;
+use crate::email_client::mime::build_message;
+use crate::email_client::{EmailError, EmailErrorKind, EmailMessage, EmailSender};
+use lettre::transport::smtp::authentication::{Credentials, Mechanism};
+use lettre::transport::smtp::response::{Category, Severity};
+use lettre::transport::smtp::PoolConfig;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+use secrecy::ExposeSecret;
+
+// Sends emails through an SMTP relay.
+// Connections are pooled by 'lettre': we don't pay for a new
+// TCP (and TLS) handshake on every email the worker sends.
+pub struct SmtpEmailClient {
+    sender: SubscriberEmail,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpEmailClient {
+    pub fn new(
+        settings: SmtpSettings,
+        sender: SubscriberEmail,
+        timeout: std::time::Duration,
+    ) -> Result<Self, lettre::transport::smtp::Error> {
+        let builder = match settings.tls {
+            SmtpTls::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&settings.host)?,
+            SmtpTls::Implicit => AsyncSmtpTransport::<Tokio1Executor>::relay(&settings.host)?,
+            SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&settings.host),
+        };
+        let mut builder = builder
+            .port(settings.port)
+            .timeout(Some(timeout))
+            .pool_config(PoolConfig::new().max_size(settings.max_connections));
+        if let (Some(username), Some(password)) = (settings.username, settings.password) {
+            builder = builder.credentials(Credentials::new(
+                username,
+                password.expose_secret().to_owned(),
+            ));
+        }
+        if let Some(mechanism) = settings.auth_mechanism {
+            builder = builder.authentication(vec![match mechanism {
+                SmtpAuthMechanism::Plain => Mechanism::Plain,
+                SmtpAuthMechanism::Login => Mechanism::Login,
+            }]);
+        }
+        Ok(Self {
+            sender,
+            transport: builder.build(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailSender for SmtpEmailClient {
+    async fn send(&self, message: &EmailMessage) -> Result<(), EmailError> {
+        let message = build_message(&self.sender, message)?;
+        self.transport.send(message).await.map_err(smtp_error)?;
+        Ok(())
+    }
+}
+
+// Relays answer with a reply code, e.g. "550 5.1.1 No such user":
+// 4xx are transient, 5xx permanent, and 55x are about the mailbox.
+fn smtp_error(e: lettre::transport::smtp::Error) -> EmailError {
+    let code = match e.status() {
+        Some(code) => code,
+        // The TLS handshake comes before anything is sent
+        None if e.is_tls() => return EmailError::NotSent(e.into()),
+        // No reply: timeouts, connection errors
+        None => return EmailError::Unreachable(e.into()),
+    };
+    let kind = match (code.severity, code.category) {
+        (Severity::PermanentNegativeCompletion, Category::MailSystem) => EmailErrorKind::Recipient,
+        (Severity::PermanentNegativeCompletion, _) => EmailErrorKind::Permanent,
+        _ => EmailErrorKind::Transient,
+    };
+    EmailError::Rejected {
+        kind,
+        status: None,
+        error_code: code.to_string().parse().ok(),
+        message: e.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::{SmtpAuthMechanism, SmtpSettings, SmtpTls};
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{EmailErrorKind, EmailSender, SmtpEmailClient};
+    use claim::{assert_err, assert_ok};
+    use secrecy::Secret;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    #[derive(Clone, Debug)]
+    struct ReceivedEmail {
+        // e.g. "PLAIN user:password"
+        auth: Option<String>,
+        rcpt_to: Vec<String>,
+        data: String,
+    }
+
+    // Just enough of an SMTP server to receive what we send:
+    // it accepts every command and keeps the messages in memory.
+    struct SmtpStandIn {
+        port: u16,
+        received: Arc<Mutex<Vec<ReceivedEmail>>>,
+        connections: Arc<AtomicUsize>,
+    }
+
+    impl SmtpStandIn {
+        async fn start(reject_recipients: bool) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let received = Arc::new(Mutex::new(Vec::new()));
+            let connections = Arc::new(AtomicUsize::new(0));
+            let (r, c) = (received.clone(), connections.clone());
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    c.fetch_add(1, Ordering::SeqCst);
+                    tokio::spawn(serve(stream, r.clone(), reject_recipients));
+                }
+            });
+            Self {
+                port,
+                received,
+                connections,
+            }
+        }
+
+        fn received(&self) -> Vec<ReceivedEmail> {
+            self.received.lock().unwrap().clone()
+        }
+    }
+
+    async fn serve(
+        stream: tokio::net::TcpStream,
+        received: Arc<Mutex<Vec<ReceivedEmail>>>,
+        reject_recipients: bool,
+    ) {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        let decode = |s: &str| String::from_utf8(base64::decode(s.trim()).unwrap()).unwrap();
+        let mut auth = None;
+        let mut rcpt_to = Vec::new();
+        writer.write_all(b"220 localhost ESMTP stand-in\r\n").await.unwrap();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let command = line.to_uppercase();
+            let reply: &[u8] = if command.starts_with("EHLO") {
+                b"250-localhost\r\n250-AUTH PLAIN LOGIN\r\n250 8BITMIME\r\n"
+            } else if let Some(initial_response) = line.strip_prefix("AUTH PLAIN ") {
+                // "\0username\0password"
+                let decoded = decode(initial_response);
+                let mut parts = decoded.split('\0').skip(1);
+                auth = Some(format!(
+                    "PLAIN {}:{}",
+                    parts.next().unwrap(),
+                    parts.next().unwrap()
+                ));
+                b"235 2.7.0 Authentication successful\r\n"
+            } else if command == "AUTH LOGIN" {
+                // base64 for "Username:" and "Password:"
+                writer.write_all(b"334 VXNlcm5hbWU6\r\n").await.unwrap();
+                let username = decode(&lines.next_line().await.unwrap().unwrap());
+                writer.write_all(b"334 UGFzc3dvcmQ6\r\n").await.unwrap();
+                let password = decode(&lines.next_line().await.unwrap().unwrap());
+                auth = Some(format!("LOGIN {}:{}", username, password));
+                b"235 2.7.0 Authentication successful\r\n"
+            } else if command.starts_with("RCPT TO:") && reject_recipients {
+                b"550 5.1.1 No such user\r\n"
+            } else if command.starts_with("RCPT TO:") {
+                rcpt_to.push(line["RCPT TO:".len()..].to_string());
+                b"250 OK\r\n"
+            } else if command == "DATA" {
+                writer.write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n").await.unwrap();
+                let mut data = String::new();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    if line == "." {
+                        break;
+                    }
+                    data.push_str(&line);
+                    data.push('\n');
+                }
+                received.lock().unwrap().push(ReceivedEmail {
+                    auth: auth.clone(),
+                    rcpt_to: std::mem::take(&mut rcpt_to),
+                    data,
+                });
+                b"250 OK\r\n"
+            } else if command == "QUIT" {
+                writer.write_all(b"221 Bye\r\n").await.unwrap();
+                return;
+            } else {
+                // MAIL FROM, RSET, NOOP...
+                b"250 OK\r\n"
+            };
+            writer.write_all(reply).await.unwrap();
+        }
+    }
+
+    fn settings(port: u16) -> SmtpSettings {
+        SmtpSettings {
+            host: "127.0.0.1".into(),
+            port,
+            tls: SmtpTls::None,
+            username: None,
+            password: None,
+            auth_mechanism: None,
+            max_connections: 2,
+        }
+    }
+
+    fn email_client(settings: SmtpSettings) -> SmtpEmailClient {
+        let sender = SubscriberEmail::parse("newsletter@example.com".into()).unwrap();
+        SmtpEmailClient::new(settings, sender, std::time::Duration::from_secs(5)).unwrap()
+    }
+
+    fn recipient() -> SubscriberEmail {
+        SubscriberEmail::parse("ursula@example.com".into()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn send_email_sends_a_multipart_alternative_message() {
+        // Arrange
+        let server = SmtpStandIn::start(false).await;
+        let email_client = email_client(settings(server.port));
+
+        // Act
+        let outcome = email_client
+            .send_email(recipient(), "Welcome!", "<p>Hello there</p>", "Hello there")
+            .await;
+
+        // Assert
+        assert_ok!(outcome);
+        let received = server.received();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].rcpt_to, vec!["<ursula@example.com>"]);
+        let data = &received[0].data;
+        assert!(data.contains("Subject: Welcome!"));
+        assert!(data.contains("From: newsletter@example.com"));
+        assert!(data.contains("Content-Type: multipart/alternative"));
+        assert!(data.contains("Content-Type: text/plain; charset=utf-8"));
+        assert!(data.contains("Content-Type: text/html; charset=utf-8"));
+        assert!(data.contains("Hello there"));
+        assert!(data.contains("<p>Hello there</p>"));
+    }
+
+    #[tokio::test]
+    async fn send_newsletter_attaches_the_list_unsubscribe_headers() {
+        // Arrange
+        let server = SmtpStandIn::start(false).await;
+        let email_client = email_client(settings(server.port));
+
+        // Act
+        let outcome = email_client
+            .send_newsletter(
+                recipient(),
+                "Issue #1",
+                "<p>Hi</p>",
+                "Hi",
+                "https://example.com/unsubscribe",
+            )
+            .await;
+
+        // Assert
+        assert_ok!(outcome);
+        let data = &server.received()[0].data;
+        assert!(data.contains("List-Unsubscribe: <https://example.com/unsubscribe>"));
+        assert!(data.contains("List-Unsubscribe-Post: List-Unsubscribe=One-Click"));
+    }
+
+    #[tokio::test]
+    async fn authenticates_with_the_configured_mechanism() {
+        for (mechanism, expected) in [
+            (SmtpAuthMechanism::Plain, "PLAIN newsletter:secret"),
+            (SmtpAuthMechanism::Login, "LOGIN newsletter:secret"),
+        ] {
+            // Arrange
+            let server = SmtpStandIn::start(false).await;
+            let email_client = email_client(SmtpSettings {
+                username: Some("newsletter".into()),
+                password: Some(Secret::new("secret".into())),
+                auth_mechanism: Some(mechanism),
+                ..settings(server.port)
+            });
+
+            // Act
+            let outcome = email_client
+                .send_email(recipient(), "Welcome!", "<p>Hi</p>", "Hi")
+                .await;
+
+            // Assert
+            assert_ok!(outcome);
+            assert_eq!(server.received()[0].auth.as_deref(), Some(expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn connections_are_reused_across_emails() {
+        // Arrange
+        let server = SmtpStandIn::start(false).await;
+        let email_client = email_client(settings(server.port));
+
+        // Act
+        for _ in 0..3 {
+            assert_ok!(
+                email_client
+                    .send_email(recipient(), "Welcome!", "<p>Hi</p>", "Hi")
+                    .await
+            );
+            // Connections go back to the pool from a background task
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+
+        // Assert
+        assert_eq!(server.received().len(), 3);
+        assert_eq!(server.connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_if_the_recipient_is_rejected() {
+        // Arrange
+        let server = SmtpStandIn::start(true).await;
+        let email_client = email_client(settings(server.port));
+
+        // Act
+        let outcome = email_client
+            .send_email(recipient(), "Welcome!", "<p>Hi</p>", "Hi")
+            .await;
+
+        // Assert
+        let e = assert_err!(outcome);
+        assert_eq!(e.kind(), EmailErrorKind::Recipient);
+        assert_eq!(e.error_code(), Some(550));
+    }
+}
@@ -0,0 +1,191 @@
+use std::sync::Mutex;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::time::{Duration, Instant};
+use tracing::Span;
+
+// Client-side rate limiting for the email API:
+// - a token bucket caps the number of requests per second;
+// - a semaphore caps the number of requests in flight;
+// - a pause, set when the provider asks us to back off (HTTP 429).
+// A big issue then goes out at the pace the provider accepts,
+// instead of failing deliveries we would have to retry anyway.
+pub struct Throttle {
+    bucket: Option<Mutex<TokenBucket>>,
+    in_flight: Semaphore,
+    max_in_flight: usize,
+    paused_until: Mutex<Option<Instant>>,
+}
+
+struct TokenBucket {
+    // how many requests we can fire at once after a quiet period
+    capacity: f64,
+    tokens: f64,
+    refill_per_second: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    // Take a token, or tell how long it takes for the next one to be available.
+    fn try_take(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_second).min(self.capacity);
+        self.last_refill = now;
+        if self.tokens >= 1. {
+            self.tokens -= 1.;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1. - self.tokens) / self.refill_per_second,
+            ))
+        }
+    }
+}
+
+impl Throttle {
+    pub fn new(requests_per_second: u32, max_in_flight: usize) -> Self {
+        let requests_per_second = f64::from(requests_per_second.max(1));
+        Self {
+            bucket: Some(Mutex::new(TokenBucket {
+                capacity: requests_per_second,
+                tokens: requests_per_second,
+                refill_per_second: requests_per_second,
+                last_refill: Instant::now(),
+            })),
+            in_flight: Semaphore::new(max_in_flight.max(1)),
+            max_in_flight: max_in_flight.max(1),
+            paused_until: Mutex::new(None),
+        }
+    }
+
+    // No limits, but we still honour 'Retry-After'
+    pub fn unlimited() -> Self {
+        Self {
+            bucket: None,
+            in_flight: Semaphore::new(Semaphore::MAX_PERMITS),
+            max_in_flight: Semaphore::MAX_PERMITS,
+            paused_until: Mutex::new(None),
+        }
+    }
+
+    // Wait until we are allowed to fire a request.
+    // The request counts as in flight until the permit is dropped.
+    #[tracing::instrument(
+        name = "Wait for the email rate limiter",
+        skip_all,
+        fields(
+            throttle.in_flight = tracing::field::Empty,
+            throttle.available_tokens = tracing::field::Empty,
+            throttle.paused = tracing::field::Empty,
+            throttle.waited_ms = tracing::field::Empty,
+        )
+    )]
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        let start = Instant::now();
+        let permit = self
+            .in_flight
+            .acquire()
+            .await
+            .expect("The semaphore is never closed.");
+        let mut paused = false;
+        loop {
+            let now = Instant::now();
+            let paused_until = *self.paused_until.lock().unwrap();
+            if let Some(paused_until) = paused_until.filter(|t| *t > now) {
+                paused = true;
+                tokio::time::sleep_until(paused_until).await;
+                continue;
+            }
+            let wait = match &self.bucket {
+                Some(bucket) => bucket.lock().unwrap().try_take(now),
+                None => Ok(()),
+            };
+            match wait {
+                Ok(()) => break,
+                Err(wait) => tokio::time::sleep(wait).await,
+            }
+        }
+        let span = Span::current();
+        span.record(
+            "throttle.in_flight",
+            self.max_in_flight - self.in_flight.available_permits(),
+        );
+        if let Some(bucket) = &self.bucket {
+            span.record(
+                "throttle.available_tokens",
+                bucket.lock().unwrap().tokens.floor() as u64,
+            );
+        }
+        span.record("throttle.paused", paused);
+        span.record("throttle.waited_ms", start.elapsed().as_millis() as u64);
+        permit
+    }
+
+    // The provider asked us to back off: no request goes out
+    // before 'duration' has elapsed, whoever fires it.
+    pub fn pause_for(&self, duration: Duration) {
+        let until = Instant::now() + duration;
+        let mut paused_until = self.paused_until.lock().unwrap();
+        if paused_until.map_or(true, |t| t < until) {
+            *paused_until = Some(until);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Throttle;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn requests_above_the_rate_wait_for_a_token() {
+        // Arrange
+        let throttle = Throttle::new(20, 100);
+        let start = Instant::now();
+
+        // Act - 20 tokens in the bucket, then one every 50ms
+        for _ in 0..25 {
+            drop(throttle.acquire().await);
+        }
+
+        // Assert
+        assert!(start.elapsed() >= Duration::from_millis(240));
+    }
+
+    #[tokio::test]
+    async fn the_number_of_requests_in_flight_is_capped() {
+        // Arrange
+        let throttle = Arc::new(Throttle::new(1000, 2));
+        let first = throttle.acquire().await;
+        let _second = throttle.acquire().await;
+
+        // Act
+        let t = throttle.clone();
+        let third = tokio::spawn(async move {
+            drop(t.acquire().await);
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // Assert
+        assert!(!third.is_finished());
+        drop(first);
+        tokio::time::timeout(Duration::from_secs(1), third)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn nothing_goes_out_while_paused() {
+        // Arrange
+        let throttle = Throttle::unlimited();
+        throttle.pause_for(Duration::from_millis(200));
+        let start = Instant::now();
+
+        // Act
+        drop(throttle.acquire().await);
+
+        // Assert
+        assert!(start.elapsed() >= Duration::from_millis(190));
+    }
+}
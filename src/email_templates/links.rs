@@ -0,0 +1,103 @@
+use super::escape_html;
+use super::plain_text::decode_entities;
+
+// Replace the target of every '<a href="...">' in 'html'.
+// 'rewrite' gets the decoded target and returns the new one,
+// or 'None' to leave the link alone.
+// Like 'html_to_text', it only knows enough HTML for our own emails.
+pub fn rewrite_links(html: &str, mut rewrite: impl FnMut(&str) -> Option<String>) -> String {
+    let mut output = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        let end = rest[start..].find('>').map_or(rest.len(), |end| start + end + 1);
+        output.push_str(&rest[..start]);
+        let tag = &rest[start..end];
+        match href(tag) {
+            Some((value_start, value_end)) => {
+                let target = decode_entities(&tag[value_start..value_end]);
+                match rewrite(&target) {
+                    Some(target) => {
+                        output.push_str(&tag[..value_start]);
+                        output.push_str(&escape_html(&target));
+                        output.push_str(&tag[value_end..]);
+                    }
+                    None => output.push_str(tag),
+                }
+            }
+            None => output.push_str(tag),
+        }
+        rest = &rest[end..];
+    }
+    output.push_str(rest);
+    output
+}
+
+// Where the value of the 'href' attribute starts and ends, for an '<a>' tag
+fn href(tag: &str) -> Option<(usize, usize)> {
+    // ASCII-only lowercasing keeps the byte offsets unchanged
+    let lowercase = tag.to_ascii_lowercase();
+    let name = lowercase[1..].split(|c: char| c.is_whitespace()).next()?;
+    if name != "a" {
+        return None;
+    }
+    let mut search_from = 0;
+    let start = loop {
+        let found = search_from + lowercase[search_from..].find("href=")?;
+        if lowercase[..found].ends_with(char::is_whitespace) {
+            break found + "href=".len();
+        }
+        search_from = found + 1;
+    };
+    match tag[start..].chars().next()? {
+        quote @ ('"' | '\'') => {
+            let end = tag[start + 1..].find(quote)? + start + 1;
+            Some((start + 1, end))
+        }
+        _ => {
+            let end = tag[start..]
+                .find(|c: char| c.is_whitespace() || c == '>')
+                .map_or(tag.len(), |end| start + end);
+            Some((start, end))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::rewrite_links;
+
+    fn tracked(url: &str) -> Option<String> {
+        Some(format!("https://t.example.com/?u={}", url))
+    }
+
+    #[test]
+    fn links_are_rewritten() {
+        let html = r#"<p>See <a style="color: red;" href="https://example.com/a?x=1&amp;y=2">this</a>.</p>"#;
+        assert_eq!(
+            rewrite_links(html, tracked),
+            r#"<p>See <a style="color: red;" href="https://t.example.com/?u=https://example.com/a?x=1&amp;y=2">this</a>.</p>"#
+        );
+    }
+
+    #[test]
+    fn single_quoted_and_unquoted_targets_are_rewritten() {
+        let html = "<a href='https://a.com'>a</a> <A HREF=https://b.com>b</A>";
+        assert_eq!(
+            rewrite_links(html, tracked),
+            "<a href='https://t.example.com/?u=https://a.com'>a</a> \
+            <A HREF=https://t.example.com/?u=https://b.com>b</A>"
+        );
+    }
+
+    #[test]
+    fn other_tags_and_attributes_are_left_alone() {
+        let html = r#"<link href="style.css"><a data-href="x" title="a">no target</a><img src="https://a.com/x.png">"#;
+        assert_eq!(rewrite_links(html, tracked), html);
+    }
+
+    #[test]
+    fn links_can_be_skipped() {
+        let html = r#"<a href="mailto:editor@example.com">mail</a>"#;
+        assert_eq!(rewrite_links(html, |_| None), html);
+    }
+}
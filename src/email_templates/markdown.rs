@@ -0,0 +1,112 @@
+use super::{html_to_text, RenderedEmail};
+use pulldown_cmark::{html, CowStr, Event, Options, Parser, Tag};
+
+// Email clients ignore (or strip) <style> blocks: every tag we emit
+// carries its own styles.
+const STYLES: &[(&str, &str)] = &[
+    ("h1", "margin: 0 0 16px; font-size: 28px; line-height: 1.25;"),
+    ("h2", "margin: 24px 0 12px; font-size: 22px; line-height: 1.3;"),
+    ("h3", "margin: 20px 0 8px; font-size: 18px; line-height: 1.3;"),
+    ("p", "margin: 0 0 16px;"),
+    ("a", "color: #2563eb; text-decoration: underline;"),
+    ("blockquote", "margin: 0 0 16px; padding-left: 12px; border-left: 4px solid #d4d4d8; color: #52525b;"),
+    ("pre", "margin: 0 0 16px; padding: 12px; background-color: #f4f4f5; overflow-x: auto;"),
+    ("code", "font-family: Menlo, Consolas, monospace; font-size: 14px;"),
+    ("ul", "margin: 0 0 16px; padding-left: 24px;"),
+    ("ol", "margin: 0 0 16px; padding-left: 24px;"),
+    ("img", "max-width: 100%; height: auto;"),
+    ("table", "margin: 0 0 16px; border-collapse: collapse;"),
+    ("th", "padding: 4px 8px; border: 1px solid #d4d4d8;"),
+    ("td", "padding: 4px 8px; border: 1px solid #d4d4d8;"),
+];
+
+// Turn the Markdown an editor wrote into the HTML and the plain-text
+// versions of an issue.
+// The HTML is safe to embed as is: raw HTML in the source is escaped,
+// and links can only point to web pages or email addresses.
+pub fn render_markdown(markdown: &str) -> RenderedEmail {
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH;
+    let events = Parser::new_ext(markdown, options).map(|event| match event {
+        // Shown as text: '<script>' is not something we want to send out
+        Event::Html(html) => Event::Text(html),
+        Event::Start(Tag::Link(kind, url, title)) => {
+            Event::Start(Tag::Link(kind, safe_url(url), title))
+        }
+        Event::Start(Tag::Image(kind, url, title)) => {
+            Event::Start(Tag::Image(kind, safe_url(url), title))
+        }
+        event => event,
+    });
+    let mut unstyled = String::new();
+    html::push_html(&mut unstyled, events);
+    let text = html_to_text(&unstyled);
+    RenderedEmail {
+        html: inline_styles(&unstyled),
+        text,
+    }
+}
+
+// 'javascript:' and friends are replaced by an empty link
+fn safe_url(url: CowStr) -> CowStr {
+    let scheme = url
+        .split([':', '/', '?', '#'])
+        .next()
+        .filter(|scheme| url[scheme.len()..].starts_with(':'));
+    match scheme.map(|s| s.trim().to_lowercase()) {
+        // relative links, e.g. anchors
+        None => url,
+        Some(scheme) if matches!(scheme.as_str(), "http" | "https" | "mailto") => url,
+        Some(_) => CowStr::Borrowed(""),
+    }
+}
+
+// Every tag in the output was generated by 'push_html' (text is escaped),
+// so we can match on them safely.
+fn inline_styles(html: &str) -> String {
+    let mut html = html.to_string();
+    for (tag, style) in STYLES {
+        html = html
+            .replace(&format!("<{}>", tag), &format!("<{} style=\"{}\">", tag, style))
+            .replace(&format!("<{} ", tag), &format!("<{} style=\"{}\" ", tag, style));
+    }
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render_markdown;
+
+    #[test]
+    fn markdown_is_rendered_to_html_with_inline_styles() {
+        let email = render_markdown("# Issue #1\n\nHello **there**, see [our site](https://example.com).");
+        assert!(email.html.contains("<h1 style=\""));
+        assert!(email.html.contains("<strong>there</strong>"));
+        assert!(email.html.contains(r#"<a style="color: #2563eb; text-decoration: underline;" href="https://example.com">our site</a>"#));
+        assert!(!email.html.contains("<p>"));
+    }
+
+    #[test]
+    fn markdown_gets_a_plain_text_version() {
+        let email = render_markdown("# Issue #1\n\nHello **there**, see [our site](https://example.com).\n\n- one\n- two");
+        assert_eq!(
+            email.text,
+            "Issue #1\n\nHello there, see our site (https://example.com).\n\n- one\n- two"
+        );
+    }
+
+    #[test]
+    fn raw_html_is_escaped() {
+        let email = render_markdown("Hi <script>alert('pwned')</script>\n\n<div onclick=\"x()\">block</div>");
+        assert!(!email.html.contains("<script>"));
+        assert!(!email.html.contains("<div"));
+        assert!(email.html.contains("&lt;script&gt;"));
+    }
+
+    #[test]
+    fn unsafe_links_are_dropped() {
+        let email = render_markdown("[click](javascript:alert(1)) [mail](mailto:editor@example.com) ![x](data:image/png;base64,AAAA)");
+        assert!(!email.html.contains("javascript:"));
+        assert!(!email.html.contains("data:"));
+        assert!(email.html.contains("mailto:editor@example.com"));
+    }
+}
@@ -0,0 +1,221 @@
+mod links;
+mod markdown;
+mod plain_text;
+mod template;
+
+pub use links::rewrite_links;
+pub use markdown::render_markdown;
+pub use plain_text::html_to_text;
+pub use template::escape_html;
+
+use crate::domain::SubscriberName;
+use anyhow::Context;
+use std::collections::HashMap;
+use std::path::Path;
+use template::{Template, Variable};
+
+// The emails we send out, one template per kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TemplateName {
+    Confirmation,
+    Welcome,
+    Newsletter,
+}
+
+impl TemplateName {
+    const ALL: [TemplateName; 3] = [
+        TemplateName::Confirmation,
+        TemplateName::Welcome,
+        TemplateName::Newsletter,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TemplateName::Confirmation => "confirmation",
+            TemplateName::Welcome => "welcome",
+            TemplateName::Newsletter => "newsletter",
+        }
+    }
+}
+
+// Our email templates, read from disk when the application starts:
+// - 'layout.html' wraps the HTML body of every email ('{{{ body }}}');
+// - '<name>.html' is the HTML body of an email;
+// - '<name>.txt', if any, its plain-text version. When it is missing we
+//   generate one from the HTML.
+// A missing file or a broken template stops the application at startup.
+#[derive(Debug)]
+pub struct EmailTemplates {
+    layout: Template,
+    templates: HashMap<TemplateName, (Template, Option<Template>)>,
+}
+
+// The values a template can use. They are all optional: a template
+// that refers to a value we do not have gets an empty string.
+#[derive(Default)]
+pub struct TemplateContext {
+    values: HashMap<Variable, String>,
+    // the HTML and the plain-text version of an issue
+    content: Option<(String, String)>,
+}
+
+#[derive(serde::Serialize)]
+pub struct RenderedEmail {
+    pub html: String,
+    pub text: String,
+}
+
+impl EmailTemplates {
+    pub fn load(directory: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let directory = directory.as_ref();
+        let layout = read_template(&directory.join("layout.html"))?
+            .context("The email layout is missing.")?;
+        let mut templates = HashMap::new();
+        for name in TemplateName::ALL {
+            let html = read_template(&directory.join(format!("{}.html", name.as_str())))?
+                .with_context(|| format!("The '{}' email template is missing.", name.as_str()))?;
+            let text = read_template(&directory.join(format!("{}.txt", name.as_str())))?;
+            templates.insert(name, (html, text));
+        }
+        Ok(Self { layout, templates })
+    }
+
+    pub fn render(&self, name: TemplateName, context: &TemplateContext) -> RenderedEmail {
+        let (html, text) = &self.templates[&name];
+        let body = html.render(|v| context.html_value(v), true);
+        let html = self.layout.render(
+            |v| match v {
+                Variable::Body => Some(body.clone()),
+                v => context.html_value(v),
+            },
+            true,
+        );
+        let text = match text {
+            Some(text) => text.render(|v| context.text_value(v), false),
+            None => html_to_text(&html),
+        };
+        RenderedEmail { html, text }
+    }
+}
+
+// 'Ok(None)' if the file does not exist
+fn read_template(path: &Path) -> Result<Option<Template>, anyhow::Error> {
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read {}.", path.display()));
+        }
+    };
+    let template = Template::parse(&source)
+        .with_context(|| format!("Invalid email template {}.", path.display()))?;
+    Ok(Some(template))
+}
+
+impl TemplateContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscriber_name(mut self, name: &SubscriberName) -> Self {
+        self.values
+            .insert(Variable::SubscriberName, name.as_ref().to_string());
+        self
+    }
+
+    pub fn confirmation_link(mut self, link: &str) -> Self {
+        self.values.insert(Variable::ConfirmationLink, link.to_string());
+        self
+    }
+
+    pub fn unsubscribe_link(mut self, link: &str) -> Self {
+        self.values.insert(Variable::UnsubscribeLink, link.to_string());
+        self
+    }
+
+    pub fn tracking_opt_out_link(mut self, link: &str) -> Self {
+        self.values.insert(Variable::TrackingOptOutLink, link.to_string());
+        self
+    }
+
+    pub fn title(mut self, title: &str) -> Self {
+        self.values.insert(Variable::Title, title.to_string());
+        self
+    }
+
+    pub fn content(mut self, html: &str, text: &str) -> Self {
+        self.content = Some((html.to_string(), text.to_string()));
+        self
+    }
+
+    fn html_value(&self, variable: Variable) -> Option<String> {
+        match variable {
+            Variable::Content => self.content.as_ref().map(|(html, _)| html.clone()),
+            v => self.values.get(&v).cloned(),
+        }
+    }
+
+    fn text_value(&self, variable: Variable) -> Option<String> {
+        match variable {
+            Variable::Content => self.content.as_ref().map(|(_, text)| text.clone()),
+            v => self.values.get(&v).cloned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EmailTemplates, TemplateContext, TemplateName};
+    use crate::domain::SubscriberName;
+    use claim::assert_err;
+
+    // The templates we ship, in the 'templates' directory
+    fn templates() -> EmailTemplates {
+        EmailTemplates::load("templates").unwrap()
+    }
+
+    #[test]
+    fn the_templates_we_ship_are_valid() {
+        templates();
+    }
+
+    #[test]
+    fn every_email_is_wrapped_in_the_layout() {
+        let context = TemplateContext::new().confirmation_link("https://example.com/confirm");
+        let email = templates().render(TemplateName::Confirmation, &context);
+        assert!(email.html.starts_with("<!DOCTYPE html>"));
+        assert!(email.html.contains(r#"href="https://example.com/confirm""#));
+        assert!(email.text.contains("https://example.com/confirm"));
+    }
+
+    #[test]
+    fn the_subscriber_name_is_escaped() {
+        let name = SubscriberName::parse("Ursula & co".into()).unwrap();
+        let context = TemplateContext::new().subscriber_name(&name);
+        let email = templates().render(TemplateName::Welcome, &context);
+        assert!(email.html.contains("Ursula &amp; co"));
+        assert!(email.text.contains("Ursula & co"));
+    }
+
+    #[test]
+    fn a_plain_text_version_is_generated_when_missing() {
+        // 'welcome.txt' does not exist
+        let context = TemplateContext::new().unsubscribe_link("https://example.com/unsubscribe");
+        let email = templates().render(TemplateName::Welcome, &context);
+        assert!(!email.text.contains('<'));
+        assert!(email.text.contains("https://example.com/unsubscribe"));
+    }
+
+    #[test]
+    fn newsletters_get_the_matching_version_of_the_content() {
+        let context = TemplateContext::new().content("<p>Hello <b>there</b></p>", "Hello *there*");
+        let email = templates().render(TemplateName::Newsletter, &context);
+        assert!(email.html.contains("<p>Hello <b>there</b></p>"));
+        assert!(email.text.contains("Hello *there*"));
+    }
+
+    #[test]
+    fn loading_fails_if_a_template_is_missing() {
+        assert_err!(EmailTemplates::load("does-not-exist"));
+    }
+}
@@ -0,0 +1,158 @@
+// A plain-text version of an HTML email, for the clients
+// (and the spam filters) that want one.
+// Not a full HTML parser: it knows about the handful of tags we
+// use in our templates, and drops everything else.
+pub fn html_to_text(html: &str) -> String {
+    let mut output = String::new();
+    // whatever is inside <head>, <style>... is not meant to be read
+    let mut hidden_depth: u32 = 0;
+    // the target of the link we are in, printed after its text
+    let mut href: Option<String> = None;
+    let mut link_start = 0;
+    let mut rest = html;
+    while !rest.is_empty() {
+        let (text, tag) = match rest.find('<') {
+            Some(start) => {
+                let end = rest[start..].find('>').map_or(rest.len(), |end| start + end + 1);
+                (&rest[..start], Some(&rest[start..end]))
+            }
+            None => (rest, None),
+        };
+        if hidden_depth == 0 {
+            push_text(&mut output, text);
+        }
+        let tag = match tag {
+            Some(tag) => tag,
+            None => break,
+        };
+        rest = &rest[text.len() + tag.len()..];
+
+        let inner = tag.trim_start_matches('<').trim_end_matches('>').trim_end_matches('/');
+        let closing = inner.starts_with('/');
+        let inner = inner.trim_start_matches('/');
+        let name = inner
+            .split(|c: char| c.is_whitespace())
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+        match name.as_str() {
+            "head" | "style" | "script" | "title" => {
+                if closing {
+                    hidden_depth = hidden_depth.saturating_sub(1);
+                } else {
+                    hidden_depth += 1;
+                }
+            }
+            _ if hidden_depth > 0 => {}
+            "br" => push_line_break(&mut output, 1),
+            "p" | "div" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "ul" | "ol" | "table"
+            | "blockquote" | "hr" => push_line_break(&mut output, 2),
+            "tr" => push_line_break(&mut output, 1),
+            "li" if !closing => {
+                push_line_break(&mut output, 1);
+                output.push_str("- ");
+            }
+            "a" if !closing => {
+                href = attribute(inner, "href");
+                link_start = output.len();
+            }
+            "a" => {
+                if let Some(href) = href.take() {
+                    let text = output[link_start..].trim();
+                    if text.is_empty() || text == href {
+                        output.truncate(link_start);
+                        push_text(&mut output, &href);
+                    } else {
+                        output.push_str(&format!(" ({})", href));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    output
+        .lines()
+        .map(str::trim)
+        .collect::<Vec<_>>()
+        .join("\n")
+        .split("\n\n")
+        .map(str::trim)
+        .filter(|paragraph| !paragraph.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+// Whitespace in HTML is not meaningful: collapse it, like a browser would.
+fn push_text(output: &mut String, text: &str) {
+    for c in decode_entities(text).chars() {
+        if !c.is_whitespace() {
+            output.push(c);
+        } else if !(output.is_empty() || output.ends_with([' ', '\n'])) {
+            output.push(' ');
+        }
+    }
+}
+
+// At least 'n' line breaks: a list right after a paragraph keeps its blank line
+fn push_line_break(output: &mut String, n: usize) {
+    let trimmed = output.trim_end_matches(['\n', ' ']).len();
+    let existing = output[trimmed..].matches('\n').count();
+    output.truncate(trimmed);
+    if !output.is_empty() {
+        output.push_str(&"\n".repeat(n.max(existing)));
+    }
+}
+
+// The value of an attribute, e.g. 'href' in 'a href="https://..."'
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let start = tag.find(&format!("{}=", name))? + name.len() + 1;
+    let value = &tag[start..];
+    let value = match value.chars().next()? {
+        quote @ ('"' | '\'') => value[1..].split(quote).next()?,
+        _ => value.split(|c: char| c.is_whitespace()).next()?,
+    };
+    Some(decode_entities(value))
+}
+
+pub(super) fn decode_entities(s: &str) -> String {
+    s.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::html_to_text;
+
+    #[test]
+    fn paragraphs_and_line_breaks_are_kept() {
+        let html = "<h1>Issue #1</h1>\n  <p>Hello\n  there,<br/>welcome!</p><p>Bye</p>";
+        assert_eq!(html_to_text(html), "Issue #1\n\nHello there,\nwelcome!\n\nBye");
+    }
+
+    #[test]
+    fn links_are_followed_by_their_target() {
+        let html = r#"<p>Click <a href="https://example.com/confirm?a=1&amp;b=2">here</a> to confirm.</p>
+            <p><a href="https://example.com">https://example.com</a></p>"#;
+        assert_eq!(
+            html_to_text(html),
+            "Click here (https://example.com/confirm?a=1&b=2) to confirm.\n\nhttps://example.com"
+        );
+    }
+
+    #[test]
+    fn head_and_styles_are_dropped() {
+        let html = "<html><head><title>Hi</title><style>p { color: red; }</style></head>\
+            <body><p>Tom &amp; Jerry</p></body></html>";
+        assert_eq!(html_to_text(html), "Tom & Jerry");
+    }
+
+    #[test]
+    fn list_items_become_bullets() {
+        let html = "<ul><li>One</li><li>Two</li></ul>";
+        assert_eq!(html_to_text(html), "- One\n- Two");
+    }
+}
@@ -0,0 +1,158 @@
+use anyhow::Context;
+
+// The values a template can refer to.
+// A closed list: a typo in a template is caught when we load it,
+// not when a subscriber gets '{{ subscriber_nmae }}' in their inbox.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Variable {
+    SubscriberName,
+    ConfirmationLink,
+    UnsubscribeLink,
+    TrackingOptOutLink,
+    // the title of a newsletter issue
+    Title,
+    // the body of a newsletter issue
+    Content,
+    // the rendered template, for the layout
+    Body,
+}
+
+impl Variable {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "subscriber_name" => Some(Self::SubscriberName),
+            "confirmation_link" => Some(Self::ConfirmationLink),
+            "unsubscribe_link" => Some(Self::UnsubscribeLink),
+            "tracking_opt_out_link" => Some(Self::TrackingOptOutLink),
+            "title" => Some(Self::Title),
+            "content" => Some(Self::Content),
+            "body" => Some(Self::Body),
+            _ => None,
+        }
+    }
+}
+
+// A template, parsed once when the application starts.
+// '{{ name }}' is replaced by the HTML-escaped value of 'name',
+// '{{{ name }}}' by the value as is: only for markup we trust,
+// e.g. the body of an issue written by one of our editors.
+#[derive(Debug)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Text(String),
+    Variable { variable: Variable, raw: bool },
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Self, anyhow::Error> {
+        let mut segments = Vec::new();
+        let mut rest = source;
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                segments.push(Segment::Text(rest[..start].to_string()));
+            }
+            let (raw, open, close) = if rest[start..].starts_with("{{{") {
+                (true, "{{{", "}}}")
+            } else {
+                (false, "{{", "}}")
+            };
+            let after_open = &rest[start + open.len()..];
+            let end = after_open
+                .find(close)
+                .with_context(|| format!("Unclosed '{}' in template.", open))?;
+            let name = after_open[..end].trim();
+            let variable = Variable::parse(name)
+                .with_context(|| format!("Unknown template variable '{}'.", name))?;
+            segments.push(Segment::Variable { variable, raw });
+            rest = &after_open[end + close.len()..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Text(rest.to_string()));
+        }
+        Ok(Self { segments })
+    }
+
+    // Variables without a value are left empty.
+    // 'escape' is off for plain-text templates.
+    pub fn render(&self, value: impl Fn(Variable) -> Option<String>, escape: bool) -> String {
+        let mut output = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Text(text) => output.push_str(text),
+                Segment::Variable { variable, raw } => {
+                    let value = value(*variable).unwrap_or_default();
+                    if escape && !raw {
+                        output.push_str(&escape_html(&value));
+                    } else {
+                        output.push_str(&value);
+                    }
+                }
+            }
+        }
+        output
+    }
+}
+
+// Anything coming from a subscriber (or an editor) must not inject markup.
+pub fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Template, Variable};
+    use claim::assert_err;
+
+    fn values(variable: Variable) -> Option<String> {
+        match variable {
+            Variable::SubscriberName => Some("<b>Ursula</b> & co".into()),
+            Variable::Content => Some("<p>Hi!</p>".into()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn variables_are_html_escaped() {
+        let template = Template::parse("<p>Hello {{ subscriber_name }}!</p>").unwrap();
+        assert_eq!(
+            template.render(values, true),
+            "<p>Hello &lt;b&gt;Ursula&lt;/b&gt; &amp; co!</p>"
+        );
+    }
+
+    #[test]
+    fn triple_braces_are_not_escaped() {
+        let template = Template::parse("<div>{{{content}}}</div>").unwrap();
+        assert_eq!(template.render(values, true), "<div><p>Hi!</p></div>");
+    }
+
+    #[test]
+    fn plain_text_is_not_escaped() {
+        let template = Template::parse("Hello {{ subscriber_name }}!").unwrap();
+        assert_eq!(template.render(values, false), "Hello <b>Ursula</b> & co!");
+    }
+
+    #[test]
+    fn missing_values_are_left_empty() {
+        let template = Template::parse("[{{ unsubscribe_link }}]").unwrap();
+        assert_eq!(template.render(values, true), "[]");
+    }
+
+    #[test]
+    fn unknown_variables_are_rejected() {
+        assert_err!(Template::parse("Hello {{ subscriber_nmae }}!"));
+    }
+
+    #[test]
+    fn unclosed_variables_are_rejected() {
+        assert_err!(Template::parse("Hello {{ subscriber_name !"));
+    }
+}
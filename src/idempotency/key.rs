@@ -0,0 +1,73 @@
+use actix_web::HttpRequest;
+
+#[derive(Debug)]
+pub struct IdempotencyKey(String);
+
+impl IdempotencyKey {
+    // Read the key from the 'Idempotency-Key' header.
+    // The header is optional: 'Ok(None)' if the client didn't send one.
+    pub fn from_request(request: &HttpRequest) -> Result<Option<Self>, String> {
+        match request.headers().get("Idempotency-Key") {
+            None => Ok(None),
+            Some(value) => {
+                let value = value
+                    .to_str()
+                    .map_err(|_| "The idempotency key must be a valid string".to_string())?;
+                Self::try_from(value.to_string()).map(Some)
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for IdempotencyKey {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        if s.is_empty() {
+            return Err("The idempotency key cannot be empty".into());
+        }
+        // Keys are stored as they are: put a bound on how much
+        // space a client can make us use.
+        let max_length = 50;
+        if s.len() >= max_length {
+            return Err(format!(
+                "The idempotency key must be shorter than {} characters",
+                max_length
+            ));
+        }
+        Ok(Self(s))
+    }
+}
+
+impl From<IdempotencyKey> for String {
+    fn from(k: IdempotencyKey) -> Self {
+        k.0
+    }
+}
+
+impl AsRef<str> for IdempotencyKey {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IdempotencyKey;
+    use claim::{assert_err, assert_ok};
+
+    #[test]
+    fn an_empty_key_is_rejected() {
+        assert_err!(IdempotencyKey::try_from("".to_string()));
+    }
+
+    #[test]
+    fn a_key_that_is_too_long_is_rejected() {
+        assert_err!(IdempotencyKey::try_from("a".repeat(50)));
+    }
+
+    #[test]
+    fn a_valid_key_is_accepted() {
+        assert_ok!(IdempotencyKey::try_from("a".repeat(49)));
+    }
+}
@@ -0,0 +1,645 @@
+use crate::configuration::Settings;
+use crate::domain::{SubscriberEmail, SubscriberName};
+use crate::email_client::{EmailError, EmailErrorKind, EmailMessage, EmailSender, MAX_BATCH_SIZE};
+use crate::email_templates::{EmailTemplates, TemplateContext, TemplateName};
+use crate::routes::{suppress, tracking_opt_out_link, unsubscribe_link};
+use crate::startup::get_connection_pool;
+use crate::tracking::{Tracker, TrackingOptions};
+use chrono::Utc;
+use rand::Rng;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::Arc;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::time::Duration;
+use tracing::Span;
+use uuid::Uuid;
+
+// The metadata key carrying the issue a newsletter email belongs to
+pub const ISSUE_ID_METADATA_KEY: &str = "newsletter_issue_id";
+
+// What happened on a single pass of the worker loop
+pub enum ExecutionOutcome {
+    TaskCompleted,
+    EmptyQueue,
+}
+
+// How often, and how patiently, we retry a failed delivery
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    // total number of attempts, the first one included
+    pub max_attempts: i16,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub jitter: Duration,
+}
+
+impl RetryPolicy {
+    // Exponential backoff: base_delay * 2^n_retries, capped at max_delay,
+    // plus a random jitter.
+    pub fn delay_for(&self, n_retries: i16) -> Duration {
+        let exponent = n_retries.clamp(0, 31) as u32;
+        let backoff = self
+            .base_delay
+            .checked_mul(2u32.pow(exponent))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        let jitter_millis = self.jitter.as_millis() as u64;
+        let jitter = if jitter_millis == 0 {
+            Duration::ZERO
+        } else {
+            Duration::from_millis(rand::thread_rng().gen_range(0..=jitter_millis))
+        };
+        backoff + jitter
+    }
+}
+
+// Runs next to the HTTP server: dequeue delivery tasks and send them out
+// until the process is stopped.
+pub async fn run_worker_until_stopped(configuration: Settings) -> Result<(), std::io::Error> {
+    let connection_pool = get_connection_pool(&configuration.database);
+    let email_client = configuration.email_client.client();
+    let email_templates = configuration
+        .email_templates
+        .load()
+        .map_err(std::io::Error::other)?;
+    let retry_policy = configuration.issue_delivery.retry_policy();
+    let tracker = Tracker::new(
+        configuration.application.base_url.clone(),
+        configuration.application.hmac_secret,
+    );
+    worker_loop(
+        connection_pool,
+        email_client,
+        email_templates,
+        tracker,
+        configuration.application.base_url,
+        retry_policy,
+        configuration.issue_delivery.batch_size,
+    )
+    .await
+}
+
+async fn worker_loop(
+    pool: PgPool,
+    email_client: Arc<dyn EmailSender>,
+    email_templates: EmailTemplates,
+    tracker: Tracker,
+    base_url: String,
+    retry_policy: RetryPolicy,
+    batch_size: usize,
+) -> Result<(), std::io::Error> {
+    loop {
+        // Scheduled issues join the queue once they are due
+        if let Err(e) = enqueue_due_issues(&pool).await {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "Failed to enqueue the scheduled issues that are due.",
+            );
+        }
+        match try_execute_task(
+            &pool,
+            email_client.as_ref(),
+            &email_templates,
+            &tracker,
+            &base_url,
+            &retry_policy,
+            batch_size,
+        )
+        .await
+        {
+            // Back off for a while if the queue is empty...
+            Ok(ExecutionOutcome::EmptyQueue) => {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+            // ...or if the database is having a hard time
+            Err(_) => {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+        }
+    }
+}
+
+// One pass of the worker: dequeue up to 'batch_size' deliveries
+// and hand them to the email client in a single batch.
+#[tracing::instrument(skip_all, fields(n_tasks = tracing::field::Empty), err)]
+pub async fn try_execute_task(
+    pool: &PgPool,
+    email_client: &dyn EmailSender,
+    email_templates: &EmailTemplates,
+    tracker: &Tracker,
+    base_url: &str,
+    retry_policy: &RetryPolicy,
+    batch_size: usize,
+) -> Result<ExecutionOutcome, sqlx::Error> {
+    let (mut transaction, tasks) = dequeue_tasks(pool, batch_size.min(MAX_BATCH_SIZE)).await?;
+    if tasks.is_empty() {
+        return Ok(ExecutionOutcome::EmptyQueue);
+    }
+    Span::current().record("n_tasks", tasks.len());
+    // Tasks in the batch usually belong to the same issue
+    let mut issues = HashMap::new();
+    let mut emails = Vec::new();
+    let mut to_send = Vec::new();
+    for task in tasks {
+        match get_recipient(pool, &task.subscriber_email).await? {
+            Some(Recipient {
+                subscriber_id,
+                email: Ok(recipient),
+                name,
+                unsubscribe_token,
+                tracking_opt_out,
+            }) => {
+                let issue = match issues.entry(task.newsletter_issue_id) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        entry.insert(get_issue(pool, task.newsletter_issue_id).await?)
+                    }
+                };
+                let unsubscribe_link = unsubscribe_link(base_url, &unsubscribe_token);
+                // Only the content is tracked: the links in the footer
+                // (unsubscribe, opt out of tracking) are left alone.
+                let html_content = if tracking_opt_out {
+                    issue.html_content.clone()
+                } else {
+                    tracker.instrument(
+                        &issue.html_content,
+                        issue.tracking,
+                        task.newsletter_issue_id,
+                        subscriber_id,
+                    )
+                };
+                let mut context = TemplateContext::new()
+                    .title(&issue.title)
+                    .content(&html_content, &issue.text_content)
+                    .unsubscribe_link(&unsubscribe_link)
+                    .tracking_opt_out_link(&tracking_opt_out_link(base_url, &unsubscribe_token));
+                if let Some(name) = &name {
+                    context = context.subscriber_name(name);
+                }
+                let email = email_templates.render(TemplateName::Newsletter, &context);
+                emails.push(
+                    EmailMessage::new(recipient, issue.title.clone(), email.html, email.text)
+                        .list_unsubscribe(&unsubscribe_link)
+                        // Echoed back by bounce webhooks, see 'routes::webhooks'
+                        .metadata(
+                            ISSUE_ID_METADATA_KEY,
+                            task.newsletter_issue_id.to_string(),
+                        ),
+                );
+                to_send.push(task);
+            }
+            Some(Recipient { email: Err(e), .. }) => {
+                tracing::error!(
+                    error.cause_chain = ?e,
+                    error.message = %e,
+                    newsletter_issue_id = %task.newsletter_issue_id,
+                    subscriber_email = %task.subscriber_email,
+                    "Skipping a confirmed subscriber. \
+                    Their stored contact details are invalid",
+                );
+                delete_task(&mut transaction, &task).await?;
+            }
+            // They unsubscribed since the issue was published
+            None => {
+                tracing::info!(
+                    newsletter_issue_id = %task.newsletter_issue_id,
+                    subscriber_email = %task.subscriber_email,
+                    "Skipping a subscriber that is no longer confirmed, or suppressed.",
+                );
+                delete_task(&mut transaction, &task).await?;
+            }
+        }
+    }
+    match email_client.send_batch(&emails).await {
+        Ok(failures) => {
+            let mut failures: HashMap<usize, EmailError> = failures
+                .into_iter()
+                .map(|failure| (failure.index, failure.error))
+                .collect();
+            for (index, task) in to_send.iter().enumerate() {
+                match failures.remove(&index) {
+                    Some(e) => handle_failure(&mut transaction, task, &e, retry_policy).await?,
+                    None => {
+                        record_delivery(&mut transaction, task).await?;
+                        delete_task(&mut transaction, task).await?;
+                    }
+                }
+            }
+        }
+        // Nothing went out: every delivery of the batch failed the same way
+        Err(e) => {
+            for task in &to_send {
+                handle_failure(&mut transaction, task, &e, retry_policy).await?;
+            }
+        }
+    }
+    transaction.commit().await?;
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+async fn handle_failure(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+    e: &EmailError,
+    retry_policy: &RetryPolicy,
+) -> Result<(), sqlx::Error> {
+    let n_attempts = task.n_retries + 1;
+    // Only transient errors are worth another try: the provider will never
+    // accept the email as it is, or to this recipient, otherwise.
+    if e.is_transient() && n_attempts < retry_policy.max_attempts {
+        tracing::warn!(
+            error.cause_chain = ?e,
+            error.message = %e,
+            error.kind = ?e.kind(),
+            error.status = ?e.status(),
+            error.code = ?e.error_code(),
+            newsletter_issue_id = %task.newsletter_issue_id,
+            subscriber_email = %task.subscriber_email,
+            n_retries = task.n_retries,
+            "Failed to deliver issue to a confirmed subscriber. \
+            Retrying later.",
+        );
+        let delay = retry_policy.delay_for(task.n_retries);
+        schedule_retry(transaction, task, delay).await
+    } else {
+        tracing::error!(
+            error.cause_chain = ?e,
+            error.message = %e,
+            error.kind = ?e.kind(),
+            error.status = ?e.status(),
+            error.code = ?e.error_code(),
+            newsletter_issue_id = %task.newsletter_issue_id,
+            subscriber_email = %task.subscriber_email,
+            n_retries = task.n_retries,
+            "Failed to deliver issue to a confirmed subscriber. \
+            Moving it to the dead letters.",
+        );
+        // The provider will refuse this address for the next issues too,
+        // just like a hard bounce reported by the webhooks.
+        if e.kind() == EmailErrorKind::Recipient {
+            suppress(&mut *transaction, &task.subscriber_email, "rejected", Some(&e.to_string()))
+                .await?;
+        }
+        move_to_dead_letters(transaction, task, &e.to_string()).await
+    }
+}
+
+type PgTransaction = Transaction<'static, Postgres>;
+
+// A row of the delivery queue
+struct DeliveryTask {
+    newsletter_issue_id: Uuid,
+    subscriber_email: String,
+    n_retries: i16,
+}
+
+// 'SKIP LOCKED' lets several workers (or replicas) share the queue:
+// a row locked by another transaction is simply ignored.
+// Tasks waiting for a retry are left alone until 'execute_after'.
+// The rows stay locked until the returned transaction ends.
+#[tracing::instrument(skip_all)]
+async fn dequeue_tasks(
+    pool: &PgPool,
+    batch_size: usize,
+) -> Result<(PgTransaction, Vec<DeliveryTask>), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let tasks = sqlx::query_as!(
+        DeliveryTask,
+        r#"
+        SELECT newsletter_issue_id, subscriber_email, n_retries
+        FROM issue_delivery_queue
+        WHERE execute_after <= now()
+        FOR UPDATE
+        SKIP LOCKED
+        LIMIT $1
+        "#,
+        batch_size as i64
+    )
+    .fetch_all(&mut transaction)
+    .await?;
+    Ok((transaction, tasks))
+}
+
+#[tracing::instrument(skip_all)]
+async fn delete_task(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        DELETE FROM issue_delivery_queue
+        WHERE
+            newsletter_issue_id = $1 AND
+            subscriber_email = $2
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email
+    )
+    .execute(&mut *transaction)
+    .await?;
+    Ok(())
+}
+
+// For the delivery statistics: a replayed dead letter
+// that goes out on a later attempt is only counted once.
+#[tracing::instrument(skip_all)]
+async fn record_delivery(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO issue_deliveries (newsletter_issue_id, subscriber_email, sent_at)
+        VALUES ($1, $2, now())
+        ON CONFLICT (newsletter_issue_id, subscriber_email) DO UPDATE
+        SET sent_at = EXCLUDED.sent_at, bounced_at = NULL
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email
+    )
+    .execute(&mut *transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn schedule_retry(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+    delay: Duration,
+) -> Result<(), sqlx::Error> {
+    let execute_after = Utc::now()
+        + chrono::Duration::from_std(delay).unwrap_or_else(|_| chrono::Duration::zero());
+    sqlx::query!(
+        r#"
+        UPDATE issue_delivery_queue
+        SET
+            n_retries = n_retries + 1,
+            execute_after = $3
+        WHERE
+            newsletter_issue_id = $1 AND
+            subscriber_email = $2
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email,
+        execute_after
+    )
+    .execute(&mut *transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn move_to_dead_letters(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+    last_error: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO issue_delivery_dead_letters (
+            newsletter_issue_id,
+            subscriber_email,
+            n_retries,
+            last_error,
+            failed_at
+        )
+        VALUES ($1, $2, $3, $4, now())
+        ON CONFLICT (newsletter_issue_id, subscriber_email) DO UPDATE
+        SET
+            n_retries = EXCLUDED.n_retries,
+            last_error = EXCLUDED.last_error,
+            failed_at = EXCLUDED.failed_at
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email,
+        task.n_retries,
+        last_error
+    )
+    .execute(&mut *transaction)
+    .await?;
+    delete_task(transaction, task).await
+}
+
+// Queue one delivery task per confirmed subscriber, and mark the issue
+// as enqueued: it can no longer be rescheduled or cancelled.
+// Suppressed addresses (see 'routes::webhooks') are left out.
+// Returns the number of delivery tasks that were queued.
+//
+// Issues sent in the subscribers' timezone get one 'execute_after' per
+// subscriber: the wall-clock time of 'scheduled_for' where they live
+// (or in UTC, if we do not know their timezone).
+#[tracing::instrument(skip(transaction))]
+pub async fn enqueue_delivery_tasks(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO issue_delivery_queue (
+            newsletter_issue_id,
+            subscriber_email,
+            execute_after
+        )
+        SELECT
+            newsletter_issues.newsletter_issue_id,
+            subscriptions.email,
+            CASE
+                WHEN newsletter_issues.send_in_subscriber_timezone
+                THEN (newsletter_issues.scheduled_for AT TIME ZONE 'UTC')
+                    AT TIME ZONE COALESCE(timezones.name, 'UTC')
+                ELSE now()
+            END
+        FROM newsletter_issues
+        CROSS JOIN subscriptions
+        -- a timezone Postgres does not know about falls back to UTC
+        -- instead of failing the whole issue
+        LEFT JOIN pg_timezone_names timezones ON timezones.name = subscriptions.timezone
+        WHERE newsletter_issues.newsletter_issue_id = $1
+            AND subscriptions.status = 'confirmed'
+            AND NOT EXISTS (
+                SELECT 1 FROM suppressions
+                WHERE suppressions.email = lower(subscriptions.email)
+            )
+        "#,
+        newsletter_issue_id,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query!(
+        r#"UPDATE newsletter_issues SET enqueued_at = now() WHERE newsletter_issue_id = $1"#,
+        newsletter_issue_id,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+// The scheduler: queue the deliveries of the scheduled issues that are due.
+// Issues sent in the subscribers' timezone are queued as soon as it is
+// time somewhere on Earth (UTC+14): every delivery then waits in the queue
+// for its own 'execute_after'.
+// 'SKIP LOCKED', like 'dequeue_tasks': several workers can run the scheduler.
+// Returns the number of issues that were queued.
+#[tracing::instrument(skip_all, fields(n_issues = tracing::field::Empty))]
+pub async fn enqueue_due_issues(pool: &PgPool) -> Result<usize, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let issues = sqlx::query!(
+        r#"
+        SELECT newsletter_issue_id
+        FROM newsletter_issues
+        WHERE enqueued_at IS NULL
+            AND cancelled_at IS NULL
+            AND scheduled_for - CASE
+                WHEN send_in_subscriber_timezone THEN interval '14 hours'
+                ELSE interval '0'
+            END <= now()
+        FOR UPDATE
+        SKIP LOCKED
+        "#,
+    )
+    .fetch_all(&mut transaction)
+    .await?;
+    for issue in &issues {
+        enqueue_delivery_tasks(&mut transaction, issue.newsletter_issue_id).await?;
+    }
+    transaction.commit().await?;
+    Span::current().record("n_issues", issues.len());
+    Ok(issues.len())
+}
+
+// Put dead letters back in the queue, with a fresh retry budget.
+// Restricted to a single issue if 'newsletter_issue_id' is set.
+// Returns the number of deliveries that were queued again.
+#[tracing::instrument(skip(pool))]
+pub async fn replay_dead_letters(
+    pool: &PgPool,
+    newsletter_issue_id: Option<Uuid>,
+) -> Result<u64, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let result = sqlx::query!(
+        r#"
+        WITH replayed AS (
+            DELETE FROM issue_delivery_dead_letters
+            WHERE $1::uuid IS NULL OR newsletter_issue_id = $1
+            RETURNING newsletter_issue_id, subscriber_email
+        )
+        INSERT INTO issue_delivery_queue (newsletter_issue_id, subscriber_email)
+        SELECT newsletter_issue_id, subscriber_email FROM replayed
+        ON CONFLICT DO NOTHING
+        "#,
+        newsletter_issue_id
+    )
+    .execute(&mut transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(result.rows_affected())
+}
+
+struct NewsletterIssue {
+    title: String,
+    text_content: String,
+    html_content: String,
+    tracking: TrackingOptions,
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_issue(pool: &PgPool, issue_id: Uuid) -> Result<NewsletterIssue, sqlx::Error> {
+    let r = sqlx::query!(
+        r#"
+        SELECT title, text_content, html_content, track_opens, track_clicks
+        FROM newsletter_issues
+        WHERE
+            newsletter_issue_id = $1
+        "#,
+        issue_id
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(NewsletterIssue {
+        title: r.title,
+        text_content: r.text_content,
+        html_content: r.html_content,
+        tracking: TrackingOptions {
+            opens: r.track_opens,
+            clicks: r.track_clicks,
+        },
+    })
+}
+
+struct Recipient {
+    // for the tracking links
+    subscriber_id: Uuid,
+    email: Result<SubscriberEmail, String>,
+    // for the templates: we can do without it
+    name: Option<SubscriberName>,
+    unsubscribe_token: String,
+    tracking_opt_out: bool,
+}
+
+// Returns 'None' if the subscriber is no longer confirmed,
+// or if their address has been suppressed since the issue was published.
+#[tracing::instrument(skip_all)]
+async fn get_recipient(pool: &PgPool, email: &str) -> Result<Option<Recipient>, sqlx::Error> {
+    let r = sqlx::query!(
+        r#"
+        SELECT id, email, name, unsubscribe_token, tracking_opt_out
+        FROM subscriptions
+        WHERE email = $1 AND status = 'confirmed'
+            AND NOT EXISTS (SELECT 1 FROM suppressions WHERE suppressions.email = lower($1))
+        "#,
+        email
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(r.map(|r| Recipient {
+        subscriber_id: r.id,
+        email: SubscriberEmail::parse(r.email),
+        name: SubscriberName::parse(r.name).ok(),
+        unsubscribe_token: r.unsubscribe_token,
+        tracking_opt_out: r.tracking_opt_out,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RetryPolicy;
+    use std::time::Duration;
+
+    fn retry_policy(jitter: Duration) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            jitter,
+        }
+    }
+
+    #[test]
+    fn the_delay_doubles_at_every_retry() {
+        let policy = retry_policy(Duration::ZERO);
+        assert_eq!(policy.delay_for(0), Duration::from_secs(1));
+        assert_eq!(policy.delay_for(1), Duration::from_secs(2));
+        assert_eq!(policy.delay_for(4), Duration::from_secs(16));
+    }
+
+    #[test]
+    fn the_delay_is_capped() {
+        let policy = retry_policy(Duration::ZERO);
+        assert_eq!(policy.delay_for(10), Duration::from_secs(60));
+        assert_eq!(policy.delay_for(i16::MAX), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn the_jitter_is_bounded() {
+        let policy = retry_policy(Duration::from_millis(500));
+        for _ in 0..100 {
+            let delay = policy.delay_for(1);
+            assert!(delay >= Duration::from_secs(2));
+            assert!(delay <= Duration::from_millis(2500));
+        }
+    }
+}
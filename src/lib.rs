@@ -1,6 +1,14 @@
+pub mod authentication;
 pub mod configuration;
 pub mod routes;
 pub mod startup;
 pub mod telemetry;
+pub mod tracking;
 pub mod domain;
-pub mod email_client;
\ No newline at end of file
+pub mod email_client;
+pub mod email_templates;
+pub mod issue_delivery_worker;
+pub mod idempotency;
+pub mod session_state;
+pub mod session_store;
+pub mod utils;
\ No newline at end of file
@@ -1,18 +1,9 @@
-use actix_web::{HttpRequest, Responder};
-use std::net::TcpListener;
-use secrecy::ExposeSecret;
-use sqlx::PgPool;
-use sqlx::postgres::PgPoolOptions;
+use std::fmt::{Debug, Display};
+use tokio::task::JoinError;
 use zero2Prod::configuration::get_configuration;
+use zero2Prod::issue_delivery_worker::run_worker_until_stopped;
+use zero2Prod::startup::Application;
 use zero2Prod::telemetry::{get_subscriber,init_subscriber};
-use zero2Prod::email_client::EmailClient;
-
-async fn greet(req: HttpRequest) -> impl Responder {
-    let name = req.match_info().get("name").unwrap_or("World");
-    format!("Hello {}!", &name)
-}
-
-use zero2Prod::startup::run;
 
 
 #[tokio::main]
@@ -21,29 +12,44 @@ async fn main() -> std::io::Result<()> {
     init_subscriber(subscriber);
 
     let configuration = get_configuration().expect("Failed to read configuration");
-    // Renamed!
-    let connection_pool = PgPoolOptions::new().acquire_timeout(std::time::Duration::from_secs(2))
-        .connect_lazy_with(configuration.database.connection_string());
-    /*
-    let connection = PgConnection::connect(&configuration.database.connection_string())
-        .await
-        .expect("Failed to connect to Postgres.");
-     */
-    let sender_email = configuration.email_client.sender()
-        .expect("Invalid sender email address.");
-
-    let timeout = configuration.email_client.timeout();
-
-    let email_client = EmailClient::new(configuration.email_client.base_url,
-    sender_email,
-        configuration.email_client.authorization_token,
-        // pass new argument from configuration
-        timeout,
-    );
+    // The pool, the email client and the listener are all
+    // wired together by 'Application::build'
+    let application = Application::build(configuration.clone()).await?;
+    // The API and the delivery worker run side by side in the same process
+    let application_task = tokio::spawn(application.run_until_stopped());
+    let worker_task = tokio::spawn(run_worker_until_stopped(configuration));
+
+    // Stop as soon as either of them exits
+    tokio::select! {
+        o = application_task => report_exit("API", o),
+        o = worker_task => report_exit("Background worker", o),
+    };
+    Ok(())
+}
 
-    let address = format!("{}:{}", configuration.application.host ,configuration.application.port);
-    // Bubble up the io::Error if we failed to bind the address
-    // Otherwise call .await on our Server
-    let listener = TcpListener::bind(address)?;
-    run(listener, connection_pool, email_client)?.await
+fn report_exit(
+    task_name: &str,
+    outcome: Result<Result<(), impl Debug + Display>, JoinError>,
+) {
+    match outcome {
+        Ok(Ok(())) => {
+            tracing::info!("{} has exited", task_name)
+        }
+        Ok(Err(e)) => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "{} failed",
+                task_name
+            )
+        }
+        Err(e) => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "{} task failed to complete",
+                task_name
+            )
+        }
+    }
 }
@@ -0,0 +1,10 @@
+use actix_web::HttpResponse;
+use crate::session_state::TypedSession;
+use crate::utils::see_other;
+
+// Purging deletes the session from the store
+// and tells the browser to drop the cookie.
+pub async fn log_out(session: TypedSession) -> HttpResponse {
+    session.log_out();
+    see_other("/login")
+}
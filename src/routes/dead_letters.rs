@@ -0,0 +1,62 @@
+use actix_web::{web, HttpResponse};
+use crate::utils::e500;
+use sqlx::PgPool;
+use uuid::Uuid;
+use crate::issue_delivery_worker::replay_dead_letters;
+
+#[derive(serde::Serialize)]
+pub struct DeadLetter {
+    newsletter_issue_id: Uuid,
+    subscriber_email: String,
+    n_retries: i16,
+    last_error: String,
+    failed_at: String,
+}
+
+// Deliveries the worker gave up on, most recent first
+#[tracing::instrument(name = "List dead letters", skip(pool))]
+pub async fn list_dead_letters(pool: web::Data<PgPool>) -> Result<HttpResponse, actix_web::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT newsletter_issue_id, subscriber_email, n_retries, last_error, failed_at
+        FROM issue_delivery_dead_letters
+        ORDER BY failed_at DESC
+        "#,
+    )
+    .fetch_all(pool.get_ref())
+    .await
+    .map_err(e500)?;
+    let dead_letters: Vec<DeadLetter> = rows
+        .into_iter()
+        .map(|r| DeadLetter {
+            newsletter_issue_id: r.newsletter_issue_id,
+            subscriber_email: r.subscriber_email,
+            n_retries: r.n_retries,
+            last_error: r.last_error,
+            failed_at: r.failed_at.to_rfc3339(),
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(dead_letters))
+}
+
+#[derive(serde::Deserialize)]
+pub struct ReplayParameters {
+    newsletter_issue_id: Option<Uuid>,
+}
+
+#[derive(serde::Serialize)]
+pub struct ReplayReport {
+    queued: u64,
+}
+
+// Send dead letters back to the delivery queue, optionally for a single issue
+#[tracing::instrument(name = "Replay dead letters", skip(parameters, pool))]
+pub async fn replay(
+    parameters: web::Query<ReplayParameters>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let queued = replay_dead_letters(&pool, parameters.newsletter_issue_id)
+        .await
+        .map_err(e500)?;
+    Ok(HttpResponse::Ok().json(ReplayReport { queued }))
+}
@@ -0,0 +1,59 @@
+use crate::email_client::{read_index, MailboxEntry};
+use crate::email_templates::escape_html;
+use crate::startup::DevMailboxDirectory;
+use crate::telemetry::spawn_blocking_with_tracing;
+use crate::utils::e500;
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse};
+
+// Only registered when the dev mailbox is the email provider.
+// Lists what the application "sent", newest first, with clickable links.
+#[tracing::instrument(name = "Dev mailbox", skip(directory))]
+pub async fn dev_mailbox(
+    directory: web::Data<DevMailboxDirectory>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let directory = directory.0.clone();
+    let entries = spawn_blocking_with_tracing(move || read_index(&directory))
+        .await
+        .map_err(e500)?
+        .map_err(e500)?;
+    let mut rows = String::new();
+    for entry in entries.iter().rev() {
+        rows.push_str(&render_entry(entry));
+    }
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Dev mailbox</title>
+</head>
+<body>
+    <h1>Dev mailbox</h1>
+    <p>{} email(s)</p>
+    <ul>
+{}    </ul>
+</body>
+</html>"#,
+            entries.len(),
+            rows
+        )))
+}
+
+fn render_entry(entry: &MailboxEntry) -> String {
+    let links: String = entry
+        .links
+        .iter()
+        .map(|link| format!(r#"<li><a href="{0}">{0}</a></li>"#, escape_html(link)))
+        .collect();
+    format!(
+        "        <li>{} - to {}: <strong>{}</strong> ({})<ul>{}</ul></li>\n",
+        escape_html(&entry.sent_at),
+        escape_html(&entry.to),
+        escape_html(&entry.subject),
+        escape_html(&entry.file),
+        links
+    )
+}
@@ -0,0 +1,82 @@
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse};
+use secrecy::Secret;
+use sqlx::PgPool;
+use crate::authentication::{validate_credentials, AuthError, Credentials};
+use crate::session_state::TypedSession;
+use crate::utils::{e500, see_other};
+
+#[derive(serde::Deserialize)]
+pub struct LoginQueryParameters {
+    error: Option<String>,
+}
+
+// The error is passed as a code rather than a message:
+// whatever ends up in the query string, we only ever
+// render text we wrote ourselves.
+pub async fn login_form(query: web::Query<LoginQueryParameters>) -> HttpResponse {
+    let error_html = match query.0.error.as_deref() {
+        Some("invalid_credentials") => "<p><i>Authentication failed.</i></p>",
+        Some(_) => "<p><i>Something went wrong, please try again.</i></p>",
+        None => "",
+    };
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Login</title>
+</head>
+<body>
+    {}
+    <form action="/login" method="post">
+        <label>Username
+            <input type="text" placeholder="Enter Username" name="username">
+        </label>
+        <label>Password
+            <input type="password" placeholder="Enter Password" name="password">
+        </label>
+        <button type="submit">Login</button>
+    </form>
+</body>
+</html>"#,
+            error_html
+        ))
+}
+
+#[derive(serde::Deserialize)]
+pub struct LoginFormData {
+    username: String,
+    password: Secret<String>,
+}
+
+#[tracing::instrument(
+    name = "Log in",
+    skip(form, pool, session),
+    fields(username = %form.username)
+)]
+pub async fn login(
+    form: web::Form<LoginFormData>,
+    pool: web::Data<PgPool>,
+    session: TypedSession,
+) -> Result<HttpResponse, actix_web::Error> {
+    let credentials = Credentials {
+        username: form.0.username,
+        password: form.0.password,
+    };
+    match validate_credentials(credentials, &pool).await {
+        Ok(user_id) => {
+            // Rotate the session key on login to prevent session fixation
+            session.renew();
+            session.insert_user_id(user_id).map_err(e500)?;
+            Ok(see_other("/admin/dashboard"))
+        }
+        Err(AuthError::InvalidCredentials(e)) => {
+            tracing::warn!(error.cause_chain = ?e, "Failed login attempt");
+            Ok(see_other("/login?error=invalid_credentials"))
+        }
+        Err(e @ AuthError::UnexpectedError(_)) => Err(e500(e)),
+    }
+}
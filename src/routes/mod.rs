@@ -1,8 +1,34 @@
 // When crate::routes is called it searches for a mod.rs file definition.
 // Similar to an index.js file.
 
+mod admin_dashboard;
+mod admin_logout;
+mod dead_letters;
+mod dev_mailbox;
 mod health_check;
+mod login;
+mod newsletter_schedule;
+mod newsletter_stats;
+mod newsletters;
 mod subscriptions;
+mod subscriptions_confirm;
+mod subscriptions_resend_confirmation;
+mod subscriptions_unsubscribe;
+mod tracking;
+mod webhooks;
 
+pub use admin_dashboard::*;
+pub use admin_logout::*;
+pub use dead_letters::*;
+pub use dev_mailbox::*;
 pub use health_check::*;
+pub use login::*;
+pub use newsletter_schedule::*;
+pub use newsletter_stats::*;
+pub use newsletters::*;
 pub use subscriptions::*;
+pub use subscriptions_confirm::*;
+pub use subscriptions_resend_confirmation::*;
+pub use subscriptions_unsubscribe::*;
+pub use tracking::*;
+pub use webhooks::*;
@@ -0,0 +1,165 @@
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+use crate::utils::error_chain_fmt;
+
+// When an issue goes out, see 'issue_delivery_worker::enqueue_due_issues'.
+#[derive(serde::Deserialize)]
+pub struct Schedule {
+    // e.g. '2023-01-02T09:00:00Z'. Right away if missing.
+    #[serde(default)]
+    pub scheduled_for: Option<DateTime<Utc>>,
+    // Deliver at the wall-clock time of 'scheduled_for' (read in UTC)
+    // in each subscriber's timezone: '09:00:00Z' is 9am wherever they are.
+    #[serde(default)]
+    pub send_in_subscriber_timezone: bool,
+}
+
+impl Schedule {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.send_in_subscriber_timezone && self.scheduled_for.is_none() {
+            return Err(
+                "'send_in_subscriber_timezone' needs a 'scheduled_for' time.".to_string(),
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(serde::Serialize)]
+pub struct ScheduleReport {
+    newsletter_issue_id: Uuid,
+    scheduled_for: String,
+}
+
+#[derive(thiserror::Error)]
+pub enum ScheduleError {
+    #[error("{0}")]
+    ValidationError(String),
+    #[error("There is no newsletter issue with this id.")]
+    UnknownIssue,
+    #[error("The issue has already been queued for delivery, or cancelled.")]
+    AlreadyDispatched,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for ScheduleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for ScheduleError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ScheduleError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            ScheduleError::UnknownIssue => StatusCode::NOT_FOUND,
+            ScheduleError::AlreadyDispatched => StatusCode::CONFLICT,
+            ScheduleError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+// Move a scheduled issue, as long as the worker has not queued it yet.
+// Without 'scheduled_for' the issue goes out on the next pass of the scheduler.
+#[tracing::instrument(name = "Reschedule a newsletter issue", skip(body, pool))]
+pub async fn reschedule_newsletter(
+    newsletter_issue_id: web::Path<Uuid>,
+    body: web::Json<Schedule>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, ScheduleError> {
+    let newsletter_issue_id = newsletter_issue_id.into_inner();
+    body.validate().map_err(ScheduleError::ValidationError)?;
+    // The scheduler locks the issue while it queues it: we either get
+    // there first, or see 'enqueued_at' once it is done.
+    let scheduled_for = sqlx::query!(
+        r#"
+        UPDATE newsletter_issues
+        SET
+            scheduled_for = COALESCE($2, now()),
+            send_in_subscriber_timezone = $3
+        WHERE newsletter_issue_id = $1
+            AND enqueued_at IS NULL
+            AND cancelled_at IS NULL
+        RETURNING scheduled_for AS "scheduled_for!"
+        "#,
+        newsletter_issue_id,
+        body.scheduled_for,
+        body.send_in_subscriber_timezone,
+    )
+    .fetch_optional(pool.get_ref())
+    .await
+    .context("Failed to reschedule the newsletter issue.")?;
+    match scheduled_for {
+        Some(r) => Ok(HttpResponse::Ok().json(ScheduleReport {
+            newsletter_issue_id,
+            scheduled_for: r.scheduled_for.to_rfc3339(),
+        })),
+        None => Err(not_pending(&pool, newsletter_issue_id).await),
+    }
+}
+
+// The issue is kept, for the record, but will never be sent.
+#[tracing::instrument(name = "Cancel a newsletter issue", skip(pool))]
+pub async fn cancel_newsletter(
+    newsletter_issue_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, ScheduleError> {
+    let newsletter_issue_id = newsletter_issue_id.into_inner();
+    let result = sqlx::query!(
+        r#"
+        UPDATE newsletter_issues
+        SET cancelled_at = now()
+        WHERE newsletter_issue_id = $1
+            AND enqueued_at IS NULL
+            AND cancelled_at IS NULL
+        "#,
+        newsletter_issue_id,
+    )
+    .execute(pool.get_ref())
+    .await
+    .context("Failed to cancel the newsletter issue.")?;
+    if result.rows_affected() == 0 {
+        return Err(not_pending(&pool, newsletter_issue_id).await);
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+// Why an issue could not be updated: it does not exist,
+// or it is past the point of no return.
+async fn not_pending(pool: &PgPool, newsletter_issue_id: Uuid) -> ScheduleError {
+    let exists = sqlx::query!(
+        r#"SELECT newsletter_issue_id FROM newsletter_issues WHERE newsletter_issue_id = $1"#,
+        newsletter_issue_id,
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to look up the newsletter issue.");
+    match exists {
+        Ok(Some(_)) => ScheduleError::AlreadyDispatched,
+        Ok(None) => ScheduleError::UnknownIssue,
+        Err(e) => e.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Schedule;
+    use claim::{assert_err, assert_ok};
+
+    #[test]
+    fn subscriber_timezones_need_a_time() {
+        let schedule: Schedule =
+            serde_json::from_str(r#"{"send_in_subscriber_timezone": true}"#).unwrap();
+        assert_err!(schedule.validate());
+        let schedule: Schedule = serde_json::from_str(
+            r#"{"scheduled_for": "2023-01-02T09:00:00Z", "send_in_subscriber_timezone": true}"#,
+        )
+        .unwrap();
+        assert_ok!(schedule.validate());
+    }
+}
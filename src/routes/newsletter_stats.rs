@@ -0,0 +1,261 @@
+use actix_web::http::header;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use sqlx::PgPool;
+use uuid::Uuid;
+use crate::utils::error_chain_fmt;
+
+#[derive(serde::Deserialize)]
+pub struct StatsParameters {
+    #[serde(default)]
+    format: StatsFormat,
+    #[serde(default)]
+    bucket: Bucket,
+}
+
+#[derive(serde::Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsFormat {
+    #[default]
+    Json,
+    // for spreadsheets
+    Csv,
+}
+
+// The width of a time-series bucket
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Bucket {
+    #[default]
+    Hour,
+    Day,
+}
+
+impl Bucket {
+    // as understood by Postgres' 'date_trunc' and intervals
+    fn as_str(&self) -> &'static str {
+        match self {
+            Bucket::Hour => "hour",
+            Bucket::Day => "day",
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+pub struct IssueStats {
+    newsletter_issue_id: Uuid,
+    title: String,
+    published_at: String,
+    totals: Totals,
+    bucket: Bucket,
+    time_series: Vec<TimeBucket>,
+}
+
+// Opens and clicks are counted once per subscriber
+#[derive(serde::Serialize)]
+pub struct Totals {
+    // still waiting in the delivery queue, retries included
+    queued: i64,
+    sent: i64,
+    // given up on, see the dead letters
+    failed: i64,
+    bounced: i64,
+    opened: i64,
+    clicked: i64,
+}
+
+// What happened in a bucket of the send window.
+// Opens and clicks are events here: a subscriber opening
+// an issue twice is counted twice.
+#[derive(serde::Serialize)]
+pub struct TimeBucket {
+    start: String,
+    sent: i64,
+    failed: i64,
+    bounced: i64,
+    opened: i64,
+    clicked: i64,
+}
+
+#[derive(thiserror::Error)]
+pub enum StatsError {
+    #[error("There is no newsletter issue with this id.")]
+    UnknownIssue,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for StatsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for StatsError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            StatsError::UnknownIssue => StatusCode::NOT_FOUND,
+            StatsError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+// How an issue is doing: '?format=csv' for a spreadsheet,
+// '?bucket=day' for a coarser time series (hourly by default).
+#[tracing::instrument(name = "Newsletter issue statistics", skip(parameters, pool))]
+pub async fn newsletter_stats(
+    newsletter_issue_id: web::Path<Uuid>,
+    parameters: web::Query<StatsParameters>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, StatsError> {
+    let newsletter_issue_id = newsletter_issue_id.into_inner();
+    let issue = sqlx::query!(
+        r#"SELECT title, published_at FROM newsletter_issues WHERE newsletter_issue_id = $1"#,
+        newsletter_issue_id,
+    )
+    .fetch_optional(pool.get_ref())
+    .await
+    .context("Failed to look up the newsletter issue.")?
+    .ok_or(StatsError::UnknownIssue)?;
+    let totals = get_totals(&pool, newsletter_issue_id)
+        .await
+        .context("Failed to count the deliveries of the issue.")?;
+    let time_series = get_time_series(&pool, newsletter_issue_id, parameters.bucket)
+        .await
+        .context("Failed to compute the time series of the issue.")?;
+    let stats = IssueStats {
+        newsletter_issue_id,
+        title: issue.title,
+        published_at: issue.published_at.to_rfc3339(),
+        totals,
+        bucket: parameters.bucket,
+        time_series,
+    };
+    Ok(match parameters.format {
+        StatsFormat::Json => HttpResponse::Ok().json(stats),
+        StatsFormat::Csv => HttpResponse::Ok()
+            .content_type("text/csv; charset=utf-8")
+            .insert_header((
+                header::CONTENT_DISPOSITION,
+                format!(
+                    r#"attachment; filename="newsletter-{}-stats.csv""#,
+                    newsletter_issue_id
+                ),
+            ))
+            .body(to_csv(&stats)),
+    })
+}
+
+#[tracing::instrument(skip(pool))]
+async fn get_totals(pool: &PgPool, newsletter_issue_id: Uuid) -> Result<Totals, sqlx::Error> {
+    let r = sqlx::query!(
+        r#"
+        SELECT
+            (SELECT COUNT(*) FROM issue_delivery_queue
+                WHERE newsletter_issue_id = $1) AS "queued!",
+            (SELECT COUNT(*) FROM issue_deliveries
+                WHERE newsletter_issue_id = $1) AS "sent!",
+            (SELECT COUNT(*) FROM issue_delivery_dead_letters
+                WHERE newsletter_issue_id = $1) AS "failed!",
+            (SELECT COUNT(*) FROM issue_deliveries
+                WHERE newsletter_issue_id = $1 AND bounced_at IS NOT NULL) AS "bounced!",
+            (SELECT COUNT(DISTINCT subscriber_email) FROM tracking_events
+                WHERE newsletter_issue_id = $1 AND kind = 'open') AS "opened!",
+            (SELECT COUNT(DISTINCT subscriber_email) FROM tracking_events
+                WHERE newsletter_issue_id = $1 AND kind = 'click') AS "clicked!"
+        "#,
+        newsletter_issue_id,
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(Totals {
+        queued: r.queued,
+        sent: r.sent,
+        failed: r.failed,
+        bounced: r.bounced,
+        opened: r.opened,
+        clicked: r.clicked,
+    })
+}
+
+// One row per bucket, from the first event to the last one:
+// quiet buckets are kept (with zeros) so the series can be charted as is.
+#[tracing::instrument(skip(pool, bucket))]
+async fn get_time_series(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+    bucket: Bucket,
+) -> Result<Vec<TimeBucket>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        WITH events AS (
+            SELECT sent_at AS at, 'sent' AS kind FROM issue_deliveries
+                WHERE newsletter_issue_id = $1
+            UNION ALL
+            SELECT bounced_at, 'bounced' FROM issue_deliveries
+                WHERE newsletter_issue_id = $1 AND bounced_at IS NOT NULL
+            UNION ALL
+            SELECT failed_at, 'failed' FROM issue_delivery_dead_letters
+                WHERE newsletter_issue_id = $1
+            UNION ALL
+            SELECT occurred_at, kind FROM tracking_events
+                WHERE newsletter_issue_id = $1
+        ),
+        buckets AS (
+            SELECT generate_series(
+                date_trunc($2, window_start),
+                date_trunc($2, window_end),
+                ('1 ' || $2)::interval
+            ) AS start
+            FROM (SELECT MIN(at) AS window_start, MAX(at) AS window_end FROM events) send_window
+        )
+        SELECT
+            buckets.start AS "start!",
+            COUNT(*) FILTER (WHERE events.kind = 'sent') AS "sent!",
+            COUNT(*) FILTER (WHERE events.kind = 'failed') AS "failed!",
+            COUNT(*) FILTER (WHERE events.kind = 'bounced') AS "bounced!",
+            COUNT(*) FILTER (WHERE events.kind = 'open') AS "opened!",
+            COUNT(*) FILTER (WHERE events.kind = 'click') AS "clicked!"
+        FROM buckets
+        LEFT JOIN events ON date_trunc($2, events.at) = buckets.start
+        GROUP BY buckets.start
+        ORDER BY buckets.start
+        "#,
+        newsletter_issue_id,
+        bucket.as_str(),
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|r| TimeBucket {
+            start: r.start.to_rfc3339(),
+            sent: r.sent,
+            failed: r.failed,
+            bounced: r.bounced,
+            opened: r.opened,
+            clicked: r.clicked,
+        })
+        .collect())
+}
+
+// A 'total' row, then one row per bucket.
+// 'queued' is a snapshot, not something that happens in a bucket:
+// it is left empty there.
+// Every field is a number or a timestamp, nothing to quote.
+fn to_csv(stats: &IssueStats) -> String {
+    let totals = &stats.totals;
+    let mut csv = String::from("period,queued,sent,failed,bounced,opened,clicked\n");
+    csv.push_str(&format!(
+        "total,{},{},{},{},{},{}\n",
+        totals.queued, totals.sent, totals.failed, totals.bounced, totals.opened, totals.clicked
+    ));
+    for bucket in &stats.time_series {
+        csv.push_str(&format!(
+            "{},,{},{},{},{},{}\n",
+            bucket.start, bucket.sent, bucket.failed, bucket.bounced, bucket.opened, bucket.clicked
+        ));
+    }
+    csv
+}
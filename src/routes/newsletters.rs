@@ -0,0 +1,215 @@
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
+use chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+use crate::authentication::UserId;
+use crate::email_templates::{
+    render_markdown, EmailTemplates, RenderedEmail, TemplateContext, TemplateName,
+};
+use crate::idempotency::{save_response, try_processing, IdempotencyKey, NextAction};
+use crate::issue_delivery_worker::enqueue_delivery_tasks;
+use crate::routes::Schedule;
+use crate::tracking::TrackingOptions;
+use crate::utils::error_chain_fmt;
+
+#[derive(serde::Deserialize)]
+pub struct BodyData {
+    title: String,
+    content: Content,
+    // e.g. '"tracking": {"opens": true, "clicks": true}'
+    #[serde(default)]
+    tracking: TrackingOptions,
+    // 'scheduled_for' and 'send_in_subscriber_timezone', both optional
+    #[serde(flatten)]
+    schedule: Schedule,
+}
+
+// Either Markdown, which we render for the editor,
+// or both versions written by hand.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+pub enum Content {
+    Markdown { markdown: String },
+    Html { html: String, text: String },
+}
+
+impl Content {
+    fn render(&self) -> RenderedEmail {
+        match self {
+            Content::Markdown { markdown } => render_markdown(markdown),
+            Content::Html { html, text } => RenderedEmail {
+                html: html.clone(),
+                text: text.clone(),
+            },
+        }
+    }
+}
+
+// what we send back to the editor once the issue is queued
+#[derive(serde::Serialize)]
+pub struct PublishReport {
+    // for '/newsletters/{id}/schedule', '/cancel' and '/admin/newsletters/{id}/stats'
+    newsletter_issue_id: Uuid,
+    // nothing is queued yet for scheduled issues
+    queued: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scheduled_for: Option<String>,
+}
+
+#[derive(thiserror::Error)]
+pub enum PublishError {
+    #[error("{0}")]
+    ValidationError(String),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for PublishError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for PublishError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PublishError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            PublishError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+// We no longer send emails from the request handler: the issue is stored
+// and one delivery task per confirmed subscriber is added to the queue.
+// The background worker takes it from there.
+//
+// Clients can send an 'Idempotency-Key' header: a retried request with the
+// same key gets the first response back instead of publishing the issue twice.
+// Keys are scoped to the authenticated user.
+#[tracing::instrument(
+    name = "Publish a newsletter issue",
+    skip(request, body, pool, user_id),
+    fields(title = %body.title, user_id = %*user_id)
+)]
+pub async fn publish_newsletter(
+    request: HttpRequest,
+    body: web::Json<BodyData>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, PublishError> {
+    let user_id = user_id.into_inner();
+    body.schedule.validate().map_err(PublishError::ValidationError)?;
+    let idempotency_key =
+        IdempotencyKey::from_request(&request).map_err(PublishError::ValidationError)?;
+    // The issue and its delivery tasks are persisted atomically.
+    let mut transaction = match &idempotency_key {
+        Some(idempotency_key) => match try_processing(&pool, idempotency_key, *user_id)
+            .await
+            .context("Failed to claim the idempotency key.")?
+        {
+            NextAction::StartProcessing(transaction) => transaction,
+            NextAction::ReturnSavedResponse(saved_response) => return Ok(saved_response),
+        },
+        None => pool
+            .begin()
+            .await
+            .context("Failed to acquire a Postgres connection from the pool")?,
+    };
+    let content = body.content.render();
+    let issue_id = insert_newsletter_issue(
+        &mut transaction,
+        &body.title,
+        &content.text,
+        &content.html,
+        body.tracking,
+        &body.schedule,
+    )
+    .await
+    .context("Failed to store newsletter issue details.")?;
+    // Scheduled issues are queued by the background worker once they are due
+    let queued = match body.schedule.scheduled_for {
+        Some(_) => 0,
+        None => enqueue_delivery_tasks(&mut transaction, issue_id)
+            .await
+            .context("Failed to enqueue delivery tasks.")?,
+    };
+    let response = HttpResponse::Ok().json(PublishReport {
+        newsletter_issue_id: issue_id,
+        queued,
+        scheduled_for: body.schedule.scheduled_for.map(|at| at.to_rfc3339()),
+    });
+    match idempotency_key {
+        // Saving the response commits the transaction
+        Some(idempotency_key) => Ok(save_response(transaction, &idempotency_key, *user_id, response)
+            .await
+            .context("Failed to save the response.")?),
+        None => {
+            transaction
+                .commit()
+                .await
+                .context("Failed to commit SQL transaction to publish a newsletter issue.")?;
+            Ok(response)
+        }
+    }
+}
+
+// What subscribers would get, without sending anything.
+// Only the unsubscribe link is missing: it is different for every subscriber.
+#[tracing::instrument(
+    name = "Preview a newsletter issue",
+    skip(body, email_templates),
+    fields(title = %body.title)
+)]
+pub async fn preview_newsletter(
+    body: web::Json<BodyData>,
+    email_templates: web::Data<EmailTemplates>,
+) -> HttpResponse {
+    let content = body.content.render();
+    let context = TemplateContext::new()
+        .title(&body.title)
+        .content(&content.html, &content.text);
+    let email = email_templates.render(TemplateName::Newsletter, &context);
+    HttpResponse::Ok().json(email)
+}
+
+#[tracing::instrument(skip_all)]
+async fn insert_newsletter_issue(
+    transaction: &mut Transaction<'_, Postgres>,
+    title: &str,
+    text_content: &str,
+    html_content: &str,
+    tracking: TrackingOptions,
+    schedule: &Schedule,
+) -> Result<Uuid, sqlx::Error> {
+    let newsletter_issue_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO newsletter_issues (
+            newsletter_issue_id,
+            title,
+            text_content,
+            html_content,
+            published_at,
+            track_opens,
+            track_clicks,
+            scheduled_for,
+            send_in_subscriber_timezone
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        "#,
+        newsletter_issue_id,
+        title,
+        text_content,
+        html_content,
+        Utc::now(),
+        tracking.opens,
+        tracking.clicks,
+        schedule.scheduled_for,
+        schedule.send_in_subscriber_timezone
+    )
+    .execute(transaction)
+    .await?;
+    Ok(newsletter_issue_id)
+}
@@ -1,17 +1,26 @@
-use actix_web::{web, HttpResponse};
-use sqlx::PgPool;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use sqlx::{PgPool, Postgres, Transaction};
 use uuid::Uuid;
 use chrono::Utc;
-use tracing::Instrument;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
 // an extension trait to provide the 'graphemes' method
 // on 'String' and '&str'
 use unicode_segmentation::UnicodeSegmentation;
-use crate::domain::{NewSubscriber, SubscriberName, SubscriberEmail};
+use crate::domain::{NewSubscriber, SubscriberName, SubscriberEmail, SubscriberTimezone};
+use crate::email_client::EmailSender;
+use crate::email_templates::{EmailTemplates, TemplateContext, TemplateName};
+use crate::startup::{ApplicationBaseUrl, SubscriptionTokenTtl};
+use crate::utils::error_chain_fmt;
 
 #[derive(serde::Deserialize)]
 pub struct FormData {
     email: String,
     name: String,
+    // e.g. 'Europe/Paris', usually filled in by the browser
+    timezone: Option<String>,
 }
 
 impl TryFrom<FormData> for NewSubscriber {
@@ -22,7 +31,11 @@ impl TryFrom<FormData> for NewSubscriber {
         // 'web::Form' is a wrapper around 'FormData'
         // 'form.0' gives us access to the underlying 'FormData'
         let email = SubscriberEmail::parse(value.email)?;
-        Ok(Self{email, name})
+        let timezone = match value.timezone.filter(|tz| !tz.trim().is_empty()) {
+            Some(tz) => Some(SubscriberTimezone::parse(tz)?),
+            None => None,
+        };
+        Ok(Self{email, name, timezone})
     }
 }
 /*
@@ -35,9 +48,35 @@ pub fn parse_subscriber(form: FormData) -> Result<NewSubscriber, String> {
 }
 */
 
+#[derive(thiserror::Error)]
+pub enum SubscribeError {
+    #[error("{0}")]
+    ValidationError(String),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for SubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+// Validation errors are the caller's fault: they get a 400 and the
+// message. Anything else is a 500. The body is the 'Display'
+// representation, the full chain is only logged.
+impl ResponseError for SubscribeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            SubscribeError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            SubscribeError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
 #[tracing::instrument (
     name = "Adding a new subscriber",
-    skip(form, pool),
+    skip(form, pool, email_client, email_templates, base_url, token_ttl),
     fields(
         subscriber_email = %form.email,
         subscriber_name = %form.name
@@ -49,19 +88,106 @@ pub fn parse_subscriber(form: FormData) -> Result<NewSubscriber, String> {
 pub async fn subscribe(
     form: web::Form<FormData>,
     pool: web::Data<PgPool>, //renamed
-) -> HttpResponse {
-    let new_subscriber = match form.0.try_into(){
-        Ok(form) => form,
-        Err(_) => return HttpResponse::BadRequest().finish(),
-    };
+    // Get the email client from the app context
+    email_client: web::Data<dyn EmailSender>,
+    email_templates: web::Data<EmailTemplates>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    token_ttl: web::Data<SubscriptionTokenTtl>,
+) -> Result<HttpResponse, SubscribeError> {
+    let new_subscriber = form.0.try_into().map_err(SubscribeError::ValidationError)?;
+    // The subscriber row and its token are written in a single transaction:
+    // we never want to end up with a pending subscriber we can't confirm.
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let subscriber_id = insert_subscriber(&mut transaction, &new_subscriber)
+        .await
+        .context("Failed to insert new subscriber in the database.")?;
+    let subscription_token = generate_subscription_token();
+    store_token(&mut transaction, subscriber_id, &subscription_token, token_ttl.0)
+        .await
+        .context("Failed to store the confirmation token for a new subscriber.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to store a new subscriber.")?;
+    // Send the confirmation link to the new subscriber.
+    send_confirmation_email(
+        email_client.get_ref(),
+        &email_templates,
+        new_subscriber.email,
+        &base_url.0,
+        &subscription_token,
+    )
+    .await
+    .context("Failed to send a confirmation email.")?;
+    Ok(HttpResponse::Ok().finish())
+}
 
-    match insert_subscriber(&pool, &new_subscriber).await
-    {
-        // handle 'ok' and 'err' paths
-        Ok(_) => HttpResponse::Ok().finish(),
-        // e handled by insert_subscriber
-        Err(_) => HttpResponse::InternalServerError().finish()
-    }
+#[tracing::instrument(
+    name = "Send a confirmation email to a new subscriber",
+    skip(email_client, email_templates, recipient, base_url, subscription_token)
+)]
+pub async fn send_confirmation_email(
+    email_client: &dyn EmailSender,
+    email_templates: &EmailTemplates,
+    recipient: SubscriberEmail,
+    base_url: &str,
+    subscription_token: &str,
+) -> Result<(), anyhow::Error> {
+    // Build a confirmation link with a dynamic root
+    let confirmation_link = format!(
+        "{}/subscriptions/confirm?subscription_token={}",
+        base_url, subscription_token
+    );
+    let email = email_templates.render(
+        TemplateName::Confirmation,
+        &TemplateContext::new().confirmation_link(&confirmation_link),
+    );
+    email_client
+        .send_email(
+            recipient,
+            "Welcome!",
+            &email.html,
+            &email.text,
+        )
+        .await?;
+    Ok(())
+}
+
+// Generate a random 25-characters-long case-sensitive subscription token.
+// 'thread_rng' is a cryptographically secure PRNG, so tokens can't be guessed.
+pub fn generate_subscription_token() -> String {
+    let mut rng = thread_rng();
+    std::iter::repeat_with(|| rng.sample(Alphanumeric))
+        .map(char::from)
+        .take(25)
+        .collect()
+}
+
+#[tracing::instrument(
+    name = "Store subscription token in the database",
+    skip(subscription_token, transaction)
+)]
+pub async fn store_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    subscription_token: &str,
+    ttl: chrono::Duration,
+) -> Result<(), sqlx::Error> {
+    let created_at = Utc::now();
+    sqlx::query!(
+        r#"INSERT INTO subscription_tokens (subscription_token, subscriber_id, created_at, expires_at)
+        VALUES ($1, $2, $3, $4)"#,
+        subscription_token,
+        subscriber_id,
+        created_at,
+        created_at + ttl,
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
 }
 
 //Returns 'true' if the input satisfies all our validation constraints
@@ -95,31 +221,31 @@ pub fn is_valid_name(s: &str) -> bool {
 //Take care of database logic
 #[tracing::instrument(
     name = "Saving new subscriber details in the database",
-    skip(new_subscriber, pool)
+    skip(new_subscriber, transaction)
 )]
 pub async fn insert_subscriber(
-    pool: &PgPool,
+    transaction: &mut Transaction<'_, Postgres>,
     new_subscriber: &NewSubscriber,
-) -> Result<(), sqlx::Error> {
+) -> Result<Uuid, sqlx::Error> {
+    let subscriber_id = Uuid::new_v4();
+    // Unsubscribe tokens never expire: they are embedded in every newsletter.
+    let unsubscribe_token = generate_subscription_token();
     sqlx::query!(
         r#"
-        INSERT INTO subscriptions (id,email,name, subscribed_at)
-        VALUES ($1,$2,$3,$4)
+        INSERT INTO subscriptions (id,email,name, subscribed_at, status, unsubscribe_token, timezone)
+        VALUES ($1,$2,$3,$4, 'pending_confirmation', $5, $6)
         "#,
-        Uuid::new_v4(),
+        subscriber_id,
         new_subscriber.email.as_ref(),
         // using 'inner_ref'!
         new_subscriber.name.as_ref(),
-        Utc::now()
+        Utc::now(),
+        unsubscribe_token,
+        new_subscriber.timezone.as_ref().map(|tz| tz.as_ref()),
         )
-        .execute(pool)
-        .await
-        .map_err(|e| {
-            tracing::error!("Failed to execute query: {:?}", e);
-            e
-        // Using the '?' operator to return early
-        // if the function failed, returning a sqlx::Error
-        // We will talk about error handling in depth later!
-        })?;
-    Ok(())
+        .execute(transaction)
+        // Errors are logged, with their context, by
+        // whoever turns them into a response.
+        .await?;
+    Ok(subscriber_id)
 }
@@ -0,0 +1,209 @@
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+use crate::domain::{SubscriberEmail, SubscriberName};
+use crate::email_client::EmailSender;
+use crate::email_templates::{EmailTemplates, TemplateContext, TemplateName};
+use crate::routes::unsubscribe_link;
+use crate::startup::ApplicationBaseUrl;
+use crate::utils::error_chain_fmt;
+
+// the query string parameters of the confirmation link
+#[derive(serde::Deserialize)]
+pub struct Parameters {
+    subscription_token: String,
+}
+
+#[derive(thiserror::Error)]
+pub enum ConfirmError {
+    #[error("This confirmation link is not valid.")]
+    UnknownToken,
+    #[error("This confirmation link has already been used.")]
+    TokenAlreadyUsed,
+    #[error("This confirmation link has expired. Please request a new one.")]
+    TokenExpired,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for ConfirmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for ConfirmError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ConfirmError::UnknownToken => StatusCode::UNAUTHORIZED,
+            ConfirmError::TokenAlreadyUsed | ConfirmError::TokenExpired => StatusCode::GONE,
+            ConfirmError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+// 'web::Query' rejects the request with a 400 if the
+// 'subscription_token' parameter is missing
+#[tracing::instrument(
+    name = "Confirm a pending subscriber",
+    skip(parameters, pool, email_client, email_templates, base_url)
+)]
+pub async fn confirm(
+    parameters: web::Query<Parameters>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<dyn EmailSender>,
+    email_templates: web::Data<EmailTemplates>,
+    base_url: web::Data<ApplicationBaseUrl>,
+) -> Result<HttpResponse, ConfirmError> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let id = consume_token(&mut transaction, &parameters.subscription_token)
+        .await
+        .context("Failed to consume the subscription token.")?;
+    let subscriber_id = match id {
+        Some(subscriber_id) => subscriber_id,
+        // The token could not be consumed: find out why, so that
+        // the subscriber knows what to do next.
+        None => {
+            let status = get_token_status(&mut transaction, &parameters.subscription_token)
+                .await
+                .context("Failed to retrieve the status of the subscription token.")?;
+            return Err(match status {
+                // Non-existing token!
+                None => ConfirmError::UnknownToken,
+                Some(TokenStatus { consumed_at: Some(_) }) => ConfirmError::TokenAlreadyUsed,
+                Some(_) => ConfirmError::TokenExpired,
+            });
+        }
+    };
+    let subscriber = confirm_subscriber(&mut transaction, subscriber_id)
+        .await
+        .context("Failed to mark the subscriber as confirmed.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to confirm a subscriber.")?;
+    // The subscription is confirmed whether or not the welcome email
+    // goes through: a failure is logged, not reported to the subscriber.
+    if let Err(e) = send_welcome_email(
+        email_client.get_ref(),
+        &email_templates,
+        &base_url.0,
+        subscriber,
+    )
+    .await
+    {
+        tracing::warn!(
+            error.cause_chain = ?e,
+            error.message = %e,
+            "Failed to send a welcome email.",
+        );
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub struct ConfirmedSubscriber {
+    email: String,
+    name: String,
+    unsubscribe_token: String,
+}
+
+#[tracing::instrument(
+    name = "Send a welcome email to a new subscriber",
+    skip_all
+)]
+async fn send_welcome_email(
+    email_client: &dyn EmailSender,
+    email_templates: &EmailTemplates,
+    base_url: &str,
+    subscriber: ConfirmedSubscriber,
+) -> Result<(), anyhow::Error> {
+    let recipient = SubscriberEmail::parse(subscriber.email).map_err(anyhow::Error::msg)?;
+    let mut context = TemplateContext::new()
+        .unsubscribe_link(&unsubscribe_link(base_url, &subscriber.unsubscribe_token));
+    // Names stored before we validated them may not parse: skip the greeting
+    if let Ok(name) = SubscriberName::parse(subscriber.name) {
+        context = context.subscriber_name(&name);
+    }
+    let email = email_templates.render(TemplateName::Welcome, &context);
+    email_client
+        .send_email(recipient, "You're in!", &email.html, &email.text)
+        .await?;
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Mark subscriber as confirmed",
+    skip(subscriber_id, transaction)
+)]
+pub async fn confirm_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<ConfirmedSubscriber, sqlx::Error> {
+    let subscriber = sqlx::query_as!(
+        ConfirmedSubscriber,
+        r#"
+        UPDATE subscriptions SET status = 'confirmed' WHERE id = $1
+        RETURNING email, name, unsubscribe_token
+        "#,
+        subscriber_id,
+    )
+    .fetch_one(transaction)
+    .await?;
+    Ok(subscriber)
+}
+
+// Marks the token as used and returns the subscriber it belongs to.
+// Returns 'None' if the token does not exist, has expired or has already
+// been used: the check and the update happen in a single statement, so two
+// concurrent requests can't both consume the same token.
+#[tracing::instrument(
+    name = "Consume subscription token",
+    skip(subscription_token, transaction)
+)]
+pub async fn consume_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscription_token: &str,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE subscription_tokens
+        SET consumed_at = now()
+        WHERE subscription_token = $1
+            AND consumed_at IS NULL
+            AND expires_at > now()
+        RETURNING subscriber_id
+        "#,
+        subscription_token,
+    )
+    .fetch_optional(transaction)
+    .await?;
+    Ok(result.map(|r| r.subscriber_id))
+}
+
+struct TokenStatus {
+    consumed_at: Option<DateTime<Utc>>,
+}
+
+#[tracing::instrument(
+    name = "Get subscription token status",
+    skip(subscription_token, transaction)
+)]
+async fn get_token_status(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscription_token: &str,
+) -> Result<Option<TokenStatus>, sqlx::Error> {
+    let result = sqlx::query_as!(
+        TokenStatus,
+        r#"SELECT consumed_at FROM subscription_tokens WHERE subscription_token = $1"#,
+        subscription_token,
+    )
+    .fetch_optional(transaction)
+    .await?;
+    Ok(result)
+}
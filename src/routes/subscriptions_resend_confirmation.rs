@@ -0,0 +1,133 @@
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+use crate::domain::SubscriberEmail;
+use crate::email_client::EmailSender;
+use crate::email_templates::EmailTemplates;
+use crate::routes::{generate_subscription_token, send_confirmation_email, store_token};
+use crate::startup::{ApplicationBaseUrl, SubscriptionTokenTtl};
+use crate::utils::error_chain_fmt;
+
+#[derive(serde::Deserialize)]
+pub struct ResendFormData {
+    email: String,
+}
+
+#[derive(thiserror::Error)]
+pub enum ResendConfirmationError {
+    #[error("{0}")]
+    ValidationError(String),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for ResendConfirmationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for ResendConfirmationError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ResendConfirmationError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            ResendConfirmationError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+// Issue a brand new confirmation token for a pending subscriber.
+// We reply with a 200 whether or not the address is on our list
+// (or already confirmed) to avoid leaking who subscribed.
+#[tracing::instrument(
+    name = "Resending a confirmation email",
+    skip(form, pool, email_client, email_templates, base_url, token_ttl),
+    fields(subscriber_email = %form.email)
+)]
+pub async fn resend_confirmation(
+    form: web::Form<ResendFormData>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<dyn EmailSender>,
+    email_templates: web::Data<EmailTemplates>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    token_ttl: web::Data<SubscriptionTokenTtl>,
+) -> Result<HttpResponse, ResendConfirmationError> {
+    let email =
+        SubscriberEmail::parse(form.0.email).map_err(ResendConfirmationError::ValidationError)?;
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let subscriber_id = match get_pending_subscriber_id(&mut transaction, &email)
+        .await
+        .context("Failed to look up the pending subscriber.")?
+    {
+        Some(subscriber_id) => subscriber_id,
+        None => {
+            tracing::info!("No pending subscriber for this address, skipping.");
+            return Ok(HttpResponse::Ok().finish());
+        }
+    };
+    // Links from older emails must stop working once a new one is sent out.
+    revoke_tokens(&mut transaction, subscriber_id)
+        .await
+        .context("Failed to revoke the outstanding subscription tokens.")?;
+    let subscription_token = generate_subscription_token();
+    store_token(&mut transaction, subscriber_id, &subscription_token, token_ttl.0)
+        .await
+        .context("Failed to store the new subscription token.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to issue a new subscription token.")?;
+    send_confirmation_email(
+        email_client.get_ref(),
+        &email_templates,
+        email,
+        &base_url.0,
+        &subscription_token,
+    )
+    .await
+        .context("Failed to send a confirmation email.")?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[tracing::instrument(
+    name = "Get pending subscriber id",
+    skip(email, transaction)
+)]
+async fn get_pending_subscriber_id(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &SubscriberEmail,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        SELECT id FROM subscriptions
+        WHERE email = $1 AND status = 'pending_confirmation'
+        FOR UPDATE
+        "#,
+        email.as_ref(),
+    )
+    .fetch_optional(transaction)
+    .await?;
+    Ok(result.map(|r| r.id))
+}
+
+#[tracing::instrument(
+    name = "Revoke outstanding subscription tokens",
+    skip(transaction)
+)]
+async fn revoke_tokens(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"DELETE FROM subscription_tokens WHERE subscriber_id = $1 AND consumed_at IS NULL"#,
+        subscriber_id,
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,135 @@
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use sqlx::PgPool;
+use crate::utils::error_chain_fmt;
+
+// the query string parameters of the unsubscribe link
+#[derive(serde::Deserialize)]
+pub struct UnsubscribeParameters {
+    unsubscribe_token: String,
+}
+
+#[derive(thiserror::Error)]
+pub enum UnsubscribeError {
+    #[error("This unsubscribe link is not valid.")]
+    UnknownToken,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for UnsubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for UnsubscribeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            UnsubscribeError::UnknownToken => StatusCode::UNAUTHORIZED,
+            UnsubscribeError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+// Build the link we embed in the body and in the 'List-Unsubscribe'
+// header of every newsletter.
+pub fn unsubscribe_link(base_url: &str, unsubscribe_token: &str) -> String {
+    format!(
+        "{}/subscriptions/unsubscribe?unsubscribe_token={}",
+        base_url, unsubscribe_token
+    )
+}
+
+// Mail scanners follow links in the body of emails: a GET must not
+// change anything, so we only ask the subscriber to confirm their choice.
+#[tracing::instrument(
+    name = "Show unsubscribe page",
+    skip(parameters, pool)
+)]
+pub async fn unsubscribe_form(
+    parameters: web::Query<UnsubscribeParameters>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    if !token_exists(&pool, &parameters.unsubscribe_token)
+        .await
+        .context("Failed to look up the unsubscribe token.")?
+    {
+        // Non-existing token!
+        return Err(UnsubscribeError::UnknownToken);
+    }
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Unsubscribe</title>
+</head>
+<body>
+    <p>Do you want to stop receiving our newsletter?</p>
+    <form action="/subscriptions/unsubscribe?unsubscribe_token={}" method="post">
+        <button type="submit">Unsubscribe</button>
+    </form>
+</body>
+</html>"#,
+            parameters.unsubscribe_token
+        )))
+}
+
+// Handles both the form above and RFC 8058 one-click requests, which
+// POST 'List-Unsubscribe=One-Click' to the URL in the 'List-Unsubscribe' header.
+// The token is all we need, so the body is ignored.
+#[tracing::instrument(
+    name = "Unsubscribe a subscriber",
+    skip(parameters, pool)
+)]
+pub async fn unsubscribe(
+    parameters: web::Query<UnsubscribeParameters>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    if mark_as_unsubscribed(&pool, &parameters.unsubscribe_token)
+        .await
+        .context("Failed to mark the subscriber as unsubscribed.")?
+    {
+        Ok(HttpResponse::Ok().finish())
+    } else {
+        Err(UnsubscribeError::UnknownToken)
+    }
+}
+
+#[tracing::instrument(
+    name = "Check unsubscribe token",
+    skip(unsubscribe_token, pool)
+)]
+async fn token_exists(pool: &PgPool, unsubscribe_token: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"SELECT id FROM subscriptions WHERE unsubscribe_token = $1"#,
+        unsubscribe_token,
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(result.is_some())
+}
+
+// Returns 'false' if no subscriber owns the token.
+// Unsubscribing twice is not an error.
+#[tracing::instrument(
+    name = "Mark subscriber as unsubscribed",
+    skip(unsubscribe_token, pool)
+)]
+async fn mark_as_unsubscribed(
+    pool: &PgPool,
+    unsubscribe_token: &str,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"UPDATE subscriptions SET status = 'unsubscribed' WHERE unsubscribe_token = $1"#,
+        unsubscribe_token,
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
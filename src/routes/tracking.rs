@@ -0,0 +1,188 @@
+use actix_web::http::header::{self, ContentType};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use sqlx::PgPool;
+use uuid::Uuid;
+use crate::routes::UnsubscribeError;
+use crate::tracking::{Tracker, TrackingToken};
+use crate::utils::error_chain_fmt;
+
+// A transparent 1x1 GIF
+const PIXEL: &[u8] = &[
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0xff, 0xff, 0xff, 0x21, 0xf9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00,
+    0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3b,
+];
+
+#[derive(thiserror::Error)]
+pub enum TrackingError {
+    #[error("This link is not valid.")]
+    InvalidLink,
+}
+
+impl std::fmt::Debug for TrackingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for TrackingError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            TrackingError::InvalidLink => StatusCode::NOT_FOUND,
+        }
+    }
+}
+
+// Loaded by the pixel at the bottom of tracked issues.
+// The image is always served: a broken image in the subscriber's
+// inbox would not help anyone, whatever went wrong on our side.
+#[tracing::instrument(name = "Track an open", skip_all)]
+pub async fn track_open(
+    token: web::Path<String>,
+    pool: web::Data<PgPool>,
+    tracker: web::Data<Tracker>,
+) -> HttpResponse {
+    if let Some(token) = tracker.verify(&token) {
+        if let Err(e) = record_event(&pool, &token, "open").await {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "Failed to record an open.",
+            );
+        }
+    }
+    HttpResponse::Ok()
+        .content_type("image/gif")
+        // every open should reach us, not a cache
+        .insert_header((header::CACHE_CONTROL, "no-store, max-age=0"))
+        .body(PIXEL)
+}
+
+// The target of every web link in tracked issues.
+// Subscribers get to the page they wanted even if we fail to record the click.
+#[tracing::instrument(name = "Track a click", skip_all)]
+pub async fn track_click(
+    token: web::Path<String>,
+    pool: web::Data<PgPool>,
+    tracker: web::Data<Tracker>,
+) -> Result<HttpResponse, TrackingError> {
+    let token = tracker.verify(&token).ok_or(TrackingError::InvalidLink)?;
+    let url = token.url.clone().ok_or(TrackingError::InvalidLink)?;
+    if let Err(e) = record_event(&pool, &token, "click").await {
+        tracing::error!(
+            error.cause_chain = ?e,
+            error.message = %e,
+            "Failed to record a click.",
+        );
+    }
+    Ok(HttpResponse::Found()
+        .insert_header((header::LOCATION, url))
+        .finish())
+}
+
+// Subscribers who opted out are not tracked, even with a link
+// from an issue sent before they did.
+#[tracing::instrument(skip(pool, token), fields(newsletter_issue_id = %token.newsletter_issue_id))]
+async fn record_event(
+    pool: &PgPool,
+    token: &TrackingToken,
+    kind: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO tracking_events (
+            event_id,
+            newsletter_issue_id,
+            subscriber_email,
+            kind,
+            url,
+            occurred_at
+        )
+        SELECT $1, $2, email, $4, $5, now()
+        FROM subscriptions
+        WHERE id = $3 AND NOT tracking_opt_out
+        "#,
+        Uuid::new_v4(),
+        token.newsletter_issue_id,
+        token.subscriber_id,
+        kind,
+        token.url,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// Subscribers are identified by their unsubscribe token,
+// like on the unsubscribe page.
+#[derive(serde::Deserialize)]
+pub struct TrackingOptOutParameters {
+    unsubscribe_token: String,
+}
+
+// The link at the bottom of every newsletter issue
+pub fn tracking_opt_out_link(base_url: &str, unsubscribe_token: &str) -> String {
+    format!(
+        "{}/subscriptions/tracking_opt_out?unsubscribe_token={}",
+        base_url, unsubscribe_token
+    )
+}
+
+// Like 'unsubscribe_form': a GET must not change anything.
+#[tracing::instrument(name = "Show tracking opt-out page", skip(parameters, pool))]
+pub async fn tracking_opt_out_form(
+    parameters: web::Query<TrackingOptOutParameters>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    let exists = sqlx::query!(
+        r#"SELECT id FROM subscriptions WHERE unsubscribe_token = $1"#,
+        parameters.unsubscribe_token,
+    )
+    .fetch_optional(pool.get_ref())
+    .await
+    .context("Failed to look up the unsubscribe token.")?
+    .is_some();
+    if !exists {
+        return Err(UnsubscribeError::UnknownToken);
+    }
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Stop tracking</title>
+</head>
+<body>
+    <p>Do you want us to stop recording when you open our emails, or click on their links?</p>
+    <form action="/subscriptions/tracking_opt_out?unsubscribe_token={}" method="post">
+        <button type="submit">Stop tracking</button>
+    </form>
+</body>
+</html>"#,
+            parameters.unsubscribe_token
+        )))
+}
+
+// Applies to the issues we send from now on, and to the
+// tracking links of the ones already in their inbox.
+#[tracing::instrument(name = "Opt a subscriber out of tracking", skip(parameters, pool))]
+pub async fn tracking_opt_out(
+    parameters: web::Query<TrackingOptOutParameters>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    let result = sqlx::query!(
+        r#"UPDATE subscriptions SET tracking_opt_out = true WHERE unsubscribe_token = $1"#,
+        parameters.unsubscribe_token,
+    )
+    .execute(pool.get_ref())
+    .await
+    .context("Failed to opt the subscriber out of tracking.")?;
+    if result.rows_affected() == 0 {
+        return Err(UnsubscribeError::UnknownToken);
+    }
+    Ok(HttpResponse::Ok().finish())
+}
@@ -0,0 +1,264 @@
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
+use chrono::Utc;
+use secrecy::ExposeSecret;
+use sqlx::{PgExecutor, PgPool};
+use std::collections::HashMap;
+use uuid::Uuid;
+use crate::authentication::basic_authentication;
+use crate::configuration::WebhookCredentials;
+use crate::issue_delivery_worker::ISSUE_ID_METADATA_KEY;
+use crate::utils::error_chain_fmt;
+
+// The header carrying the shared secret, for webhooks configured
+// without HTTP Basic credentials.
+const SECRET_HEADER: &str = "X-Webhook-Secret";
+
+// Postmark's webhook payloads, see
+// https://postmarkapp.com/developer/webhooks/webhooks-overview
+// We only care about the ones that tell us to stop emailing an address.
+#[derive(serde::Deserialize)]
+#[serde(tag = "RecordType")]
+pub enum PostmarkEvent {
+    Bounce(Bounce),
+    SpamComplaint(SpamComplaint),
+    SubscriptionChange(SubscriptionChange),
+    // Deliveries, opens, clicks...
+    #[serde(other)]
+    Other,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Bounce {
+    // e.g. 'HardBounce', 'SoftBounce', 'Transient'...
+    #[serde(rename = "Type")]
+    kind: String,
+    email: String,
+    // whether Postmark deactivated the address
+    #[serde(default)]
+    inactive: bool,
+    description: Option<String>,
+    // what we attached to the email, see 'issue_delivery_worker'
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SpamComplaint {
+    email: String,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SubscriptionChange {
+    recipient: String,
+    // 'false' when the address is reactivated
+    suppress_sending: bool,
+    // 'HardBounce', 'SpamComplaint' or 'ManualSuppression'
+    suppression_reason: Option<String>,
+}
+
+#[derive(thiserror::Error)]
+pub enum WebhookError {
+    #[error("Invalid webhook credentials.")]
+    Unauthorized(#[source] anyhow::Error),
+    #[error("Invalid webhook payload.")]
+    InvalidPayload(#[source] serde_json::Error),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for WebhookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for WebhookError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            WebhookError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            WebhookError::InvalidPayload(_) => StatusCode::BAD_REQUEST,
+            WebhookError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::new(self.status_code());
+        if let WebhookError::Unauthorized(_) = self {
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                HeaderValue::from_static(r#"Basic realm="webhooks""#),
+            );
+        }
+        response
+    }
+}
+
+// Postmark retries a webhook until it gets a 200: events we do not
+// care about are acknowledged too.
+// The body is only parsed once the caller is authenticated.
+#[tracing::instrument(name = "Handle a Postmark webhook", skip_all)]
+pub async fn postmark_webhook(
+    request: HttpRequest,
+    body: web::Bytes,
+    pool: web::Data<PgPool>,
+    credentials: web::Data<WebhookCredentials>,
+) -> Result<HttpResponse, WebhookError> {
+    authenticate(&request, &credentials).map_err(WebhookError::Unauthorized)?;
+    let event: PostmarkEvent =
+        serde_json::from_slice(&body).map_err(WebhookError::InvalidPayload)?;
+    match event {
+        PostmarkEvent::Bounce(bounce) => {
+            // Newsletter issues only: confirmation emails carry no metadata
+            if let Some(issue_id) = bounce
+                .metadata
+                .get(ISSUE_ID_METADATA_KEY)
+                .and_then(|id| Uuid::parse_str(id).ok())
+            {
+                record_bounce(&pool, issue_id, &bounce.email)
+                    .await
+                    .context("Failed to record a bounce.")?;
+            }
+            if bounce.kind == "HardBounce" || bounce.inactive {
+                suppress(
+                    pool.get_ref(),
+                    &bounce.email,
+                    "hard_bounce",
+                    bounce.description.as_deref(),
+                )
+                .await
+                .context("Failed to suppress a bounced address.")?;
+            } else {
+                tracing::info!(bounce.kind = %bounce.kind, "Not suppressing a temporary bounce.");
+            }
+        }
+        PostmarkEvent::SpamComplaint(complaint) => {
+            suppress(pool.get_ref(), &complaint.email, "spam_complaint", None)
+                .await
+                .context("Failed to suppress an address that reported us as spam.")?;
+        }
+        PostmarkEvent::SubscriptionChange(change) if change.suppress_sending => {
+            let reason = match change.suppression_reason.as_deref() {
+                Some("HardBounce") => "hard_bounce",
+                Some("SpamComplaint") => "spam_complaint",
+                _ => "manual",
+            };
+            suppress(pool.get_ref(), &change.recipient, reason, None)
+                .await
+                .context("Failed to suppress an address.")?;
+        }
+        PostmarkEvent::SubscriptionChange(change) => {
+            reactivate(&pool, &change.recipient)
+                .await
+                .context("Failed to reactivate a suppressed address.")?;
+        }
+        PostmarkEvent::Other => {}
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+// Either HTTP Basic credentials or the secret in a header
+fn authenticate(
+    request: &HttpRequest,
+    credentials: &WebhookCredentials,
+) -> Result<(), anyhow::Error> {
+    let expected = credentials.secret.expose_secret().as_bytes();
+    if let Some(secret) = request.headers().get(SECRET_HEADER) {
+        return if constant_time_eq(secret.as_bytes(), expected) {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("Invalid '{}' header.", SECRET_HEADER))
+        };
+    }
+    let basic = basic_authentication(request.headers())?;
+    if basic.username == credentials.username
+        && constant_time_eq(basic.password.expose_secret().as_bytes(), expected)
+    {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!("Invalid username or password."))
+    }
+}
+
+// Do not leak how much of the secret was right through response times
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Addresses are stored lowercased: the provider does not always report
+// them with the case our subscribers used.
+#[tracing::instrument(skip(executor, details))]
+pub async fn suppress<'c>(
+    executor: impl PgExecutor<'c>,
+    email: &str,
+    reason: &str,
+    details: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO suppressions (email, reason, details, suppressed_at)
+        VALUES (lower($1), $2, $3, $4)
+        ON CONFLICT (email) DO UPDATE
+        SET reason = EXCLUDED.reason,
+            details = EXCLUDED.details,
+            suppressed_at = EXCLUDED.suppressed_at
+        "#,
+        email,
+        reason,
+        details,
+        Utc::now(),
+    )
+    .execute(executor)
+    .await?;
+    tracing::info!("Suppressed an email address.");
+    Ok(())
+}
+
+// For the delivery statistics of the issue, see 'routes::newsletter_stats'.
+// Like suppressions, the address is matched whatever its case.
+#[tracing::instrument(skip(pool))]
+async fn record_bounce(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+    email: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE issue_deliveries
+        SET bounced_at = $3
+        WHERE newsletter_issue_id = $1 AND lower(subscriber_email) = lower($2)
+        "#,
+        newsletter_issue_id,
+        email,
+        Utc::now(),
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip(pool))]
+async fn reactivate(pool: &PgPool, email: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!(r#"DELETE FROM suppressions WHERE email = lower($1)"#, email)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::constant_time_eq;
+
+    #[test]
+    fn constant_time_eq_compares_the_whole_secret() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret-but-longer"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+}
@@ -1,12 +1,30 @@
 use actix_web::dev::Server;
+use actix_web::middleware::from_fn;
 use actix_web::{web, App, HttpServer};
 use std::net::TcpListener;
 use sqlx::{PgPool};
+use sqlx::postgres::PgPoolOptions;
 use tracing_actix_web::TracingLogger;
 
-use crate::routes::{health_check, subscribe};
+use crate::authentication::{
+    reject_anonymous_users, reject_unauthenticated_users, set_admin_password,
+};
+use crate::configuration::{DatabaseSettings, Settings, WebhookCredentials};
+use crate::routes::{
+    admin_dashboard, cancel_newsletter, confirm, dev_mailbox, health_check, list_dead_letters,
+    log_out, login, login_form, newsletter_stats, postmark_webhook, preview_newsletter,
+    publish_newsletter, replay, reschedule_newsletter, resend_confirmation, subscribe,
+    track_click, track_open, tracking_opt_out, tracking_opt_out_form, unsubscribe, unsubscribe_form,
+};
+use crate::session_store::PostgresSessionStore;
+use actix_session::SessionMiddleware;
+use actix_web::cookie::Key;
+use secrecy::{ExposeSecret, Secret};
 use actix_web::{ HttpRequest, Responder};
-use crate::email_client::EmailClient;
+use crate::email_client::EmailSender;
+use crate::email_templates::EmailTemplates;
+use crate::tracking::Tracker;
+use std::sync::Arc;
 
 
 // We need to mark `run` as public.
@@ -29,31 +47,183 @@ async fn greet(req: HttpRequest) -> impl Responder {
     format!("Hello {}!", &name)
 }
 
+// A new type to hold the newly built server and its port
+pub struct Application {
+    port: u16,
+    server: Server,
+}
+
+impl Application {
+    // We have converted the 'build' function into a constructor for
+    // 'Application'.
+    pub async fn build(configuration: Settings) -> Result<Self, std::io::Error> {
+        let connection_pool = get_connection_pool(&configuration.database);
+        if let Some(admin_password) = configuration.application.admin_password.clone() {
+            set_admin_password(admin_password, &connection_pool)
+                .await
+                .map_err(std::io::Error::other)?;
+        }
+
+        let email_client = configuration.email_client.clone().client();
+        let email_templates = configuration
+            .email_templates
+            .load()
+            .map_err(std::io::Error::other)?;
+
+        let address = format!(
+            "{}:{}",
+            configuration.application.host, configuration.application.port
+        );
+        let listener = TcpListener::bind(address)?;
+        let subscription_token_ttl = configuration.application.subscription_token_ttl();
+        let dev_mailbox_directory = configuration.email_client.dev_mailbox_directory();
+        // The OS picks the port when we ask for port 0 (tests rely on it),
+        // so we read it back from the listener.
+        let port = listener.local_addr().unwrap().port();
+        let server = run(
+            listener,
+            connection_pool,
+            email_client,
+            Arc::new(email_templates),
+            configuration.application.base_url,
+            subscription_token_ttl,
+            configuration.application.hmac_secret,
+            dev_mailbox_directory,
+            configuration.webhooks.postmark,
+        )?;
+
+        // We "save" the bound port in one of 'Application''s fields
+        Ok(Self { port, server })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    // A more expressive name that makes it clear that
+    // this function only returns when the application is stopped.
+    pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
+        self.server.await
+    }
+}
+
+pub fn get_connection_pool(configuration: &DatabaseSettings) -> PgPool {
+    PgPoolOptions::new()
+        .acquire_timeout(std::time::Duration::from_secs(2))
+        .connect_lazy_with(configuration.connection_string())
+}
+
+// We need to define a wrapper type in order to retrieve the URL
+// in the 'subscribe' handler.
+// Retrieval from the context, in actix-web, is type-based: using
+// a raw 'String' would expose us to conflicts.
+pub struct ApplicationBaseUrl(pub String);
+
+// How long a freshly issued subscription token stays valid.
+pub struct SubscriptionTokenTtl(pub chrono::Duration);
+
+// Where the dev mailbox writes its emails, for 'GET /dev/mailbox'.
+pub struct DevMailboxDirectory(pub std::path::PathBuf);
+
 // Notice the different signature!
 // We return `Server` on the happy path and we dropped the `async` keyword
 // We have no .await call, so it is not needed anymore.
-
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     listener: TcpListener,
     db_pool: PgPool,
-    email_client: EmailClient,
+    email_client: Arc<dyn EmailSender>,
+    email_templates: Arc<EmailTemplates>,
+    base_url: String,
+    subscription_token_ttl: chrono::Duration,
+    hmac_secret: Secret<String>,
+    dev_mailbox_directory: Option<std::path::PathBuf>,
+    postmark_webhook_credentials: WebhookCredentials,
 ) -> Result<Server, std::io::Error> {
     // Wrap the connection in a smart pointer
     // Wrap the pool using web::data, which boils down to an Arc smart pointer
     let db_pool = web::Data::new(db_pool);
-    let email_client = web::Data::new(email_client);
+    // 'web::Data' can wrap a trait object if we hand it the 'Arc' directly
+    let email_client: web::Data<dyn EmailSender> = web::Data::from(email_client);
+    let email_templates = web::Data::from(email_templates);
+    // Checks the signed links of '/t/o/{token}' and '/t/c/{token}'
+    let tracker = web::Data::new(Tracker::new(base_url.clone(), hmac_secret.clone()));
+    let base_url = web::Data::new(ApplicationBaseUrl(base_url));
+    let subscription_token_ttl = web::Data::new(SubscriptionTokenTtl(subscription_token_ttl));
+    let dev_mailbox_directory = dev_mailbox_directory.map(|d| web::Data::new(DevMailboxDirectory(d)));
+    let postmark_webhook_credentials = web::Data::new(postmark_webhook_credentials);
+    // Signs the session cookie
+    let secret_key = Key::from(hmac_secret.expose_secret().as_bytes());
+    let session_store = PostgresSessionStore::new(db_pool.get_ref().clone());
     // Capture 'connection' from the surrounding environment
     let server = HttpServer::new(move || {
         App::new()
             // middleware is added by using .wrap() on an app
+            .wrap(SessionMiddleware::new(
+                session_store.clone(),
+                secret_key.clone(),
+            ))
             .wrap(TracingLogger::default())
             .route("/health_check", web::get().to(health_check))
             // A new entry in our routing table for POST /subscriptions requests
             .route("/subscriptions", web::post().to(subscribe))
+            .route("/subscriptions/confirm", web::get().to(confirm))
+            .route(
+                "/subscriptions/resend_confirmation",
+                web::post().to(resend_confirmation),
+            )
+            .route("/subscriptions/unsubscribe", web::get().to(unsubscribe_form))
+            .route("/subscriptions/unsubscribe", web::post().to(unsubscribe))
+            .route(
+                "/subscriptions/tracking_opt_out",
+                web::get().to(tracking_opt_out_form),
+            )
+            .route("/subscriptions/tracking_opt_out", web::post().to(tracking_opt_out))
+            // Open and click tracking, see 'crate::tracking'
+            .route("/t/o/{token}", web::get().to(track_open))
+            .route("/t/c/{token}", web::get().to(track_click))
+            // Called by Postmark: authenticated with a shared secret, see 'postmark_webhook'
+            .service(
+                web::resource("/webhooks/postmark")
+                    .app_data(postmark_webhook_credentials.clone())
+                    .route(web::post().to(postmark_webhook)),
+            )
+            .route("/login", web::get().to(login_form))
+            .route("/login", web::post().to(login))
+            // Admin pages for logged-in editors
+            .service(
+                web::scope("/admin")
+                    .wrap(from_fn(reject_anonymous_users))
+                    .route("/dashboard", web::get().to(admin_dashboard))
+                    .route("/newsletters/{newsletter_issue_id}/stats", web::get().to(newsletter_stats))
+                    .route("/logout", web::post().to(log_out)),
+            )
+            // Admin API: only authenticated users get past the middleware
+            .service(
+                web::scope("/newsletters")
+                    .wrap(from_fn(reject_unauthenticated_users))
+                    .route("", web::post().to(publish_newsletter))
+                    .route("/preview", web::post().to(preview_newsletter))
+                    .route("/{newsletter_issue_id}/schedule", web::put().to(reschedule_newsletter))
+                    .route("/{newsletter_issue_id}/cancel", web::post().to(cancel_newsletter))
+                    .route("/dead_letters", web::get().to(list_dead_letters))
+                    .route("/dead_letters/replay", web::post().to(replay)),
+            )
+            // Local development only: see 'EmailProvider::DevMailbox'
+            .configure(|cfg| {
+                if let Some(directory) = &dev_mailbox_directory {
+                    cfg.app_data(directory.clone())
+                        .route("/dev/mailbox", web::get().to(dev_mailbox));
+                }
+            })
             .route("/{name}", web::get().to(greet))
             // Get a pointer copy and attach it to the application state
             .app_data(db_pool.clone())
             .app_data(email_client.clone())
+            .app_data(email_templates.clone())
+            .app_data(base_url.clone())
+            .app_data(tracker.clone())
+            .app_data(subscription_token_ttl.clone())
     })
     .listen(listener)?
     .run();
@@ -4,6 +4,7 @@ use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
 use tracing_subscriber::{layer::SubscriberExt, EnvFilter, Registry};
 use tracing_log::LogTracer;
 use tracing_subscriber::fmt::MakeWriter;
+use tokio::task::JoinHandle;
 
 // Compose multiple layers into a 'tracing' subscriber.
 //
@@ -54,4 +55,16 @@ pub fn init_subscriber(subscriber: impl Subscriber + Send + Sync) {
 // 'set_global_default' can be used by applications to specify
 // what subscriber should be used to process spans.
     set_global_default(subscriber).expect("Failed to set subscriber")
-}
\ No newline at end of file
+}
+
+// Run a CPU-intensive closure (e.g. password hashing) on tokio's blocking
+// thread pool, so that it does not stall the async executor.
+// The closure runs inside the caller's span to keep the logs connected.
+pub fn spawn_blocking_with_tracing<F, R>(f: F) -> JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let current_span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || current_span.in_scope(f))
+}
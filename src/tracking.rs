@@ -0,0 +1,219 @@
+use crate::email_templates::{escape_html, rewrite_links};
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use uuid::Uuid;
+
+// What an editor wants to know about an issue, see 'POST /newsletters'.
+// Both are off unless asked for.
+#[derive(serde::Deserialize, Debug, Clone, Copy, Default)]
+pub struct TrackingOptions {
+    #[serde(default)]
+    pub opens: bool,
+    #[serde(default)]
+    pub clicks: bool,
+}
+
+// Who opened (or clicked in) which issue, and where the click should take them.
+// Carried by the tracking links themselves: nothing is stored until
+// the subscriber actually opens the email.
+// Tokens are signed, not encrypted: they name the subscriber by id,
+// their address must not end up in URLs and in access logs.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+pub struct TrackingToken {
+    #[serde(rename = "i")]
+    pub newsletter_issue_id: Uuid,
+    #[serde(rename = "s")]
+    pub subscriber_id: Uuid,
+    // only set for clicks
+    #[serde(rename = "u", default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+// Builds (and checks) the tracking links we put in newsletter issues.
+// Tokens are signed: nobody can record events for someone else,
+// or turn '/t/c/{token}' into an open redirect.
+pub struct Tracker {
+    base_url: String,
+    hmac_secret: Secret<String>,
+}
+
+impl Tracker {
+    pub fn new(base_url: String, hmac_secret: Secret<String>) -> Self {
+        Self {
+            base_url,
+            hmac_secret,
+        }
+    }
+
+    // The HTML content of an issue, as sent to a single subscriber:
+    // web links go through '/t/c/{token}' and a pixel loads '/t/o/{token}'.
+    // The plain-text version is never tracked.
+    pub fn instrument(
+        &self,
+        html: &str,
+        options: TrackingOptions,
+        newsletter_issue_id: Uuid,
+        subscriber_id: Uuid,
+    ) -> String {
+        let mut html = if options.clicks {
+            rewrite_links(html, |url| {
+                if !is_web_link(url) {
+                    return None;
+                }
+                let token = self.sign(&TrackingToken {
+                    newsletter_issue_id,
+                    subscriber_id,
+                    url: Some(url.into()),
+                });
+                Some(format!("{}/t/c/{}", self.base_url, token))
+            })
+        } else {
+            html.to_string()
+        };
+        if options.opens {
+            let token = self.sign(&TrackingToken {
+                newsletter_issue_id,
+                subscriber_id,
+                url: None,
+            });
+            html.push_str(&format!(
+                r#"<img src="{}" width="1" height="1" alt="" style="display: block; border: 0;">"#,
+                escape_html(&format!("{}/t/o/{}", self.base_url, token))
+            ));
+        }
+        html
+    }
+
+    // 'None' if the token was not issued by us, or was tampered with
+    pub fn verify(&self, token: &str) -> Option<TrackingToken> {
+        let (payload, signature) = token.split_once('.')?;
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).ok()?;
+        self.mac(payload).verify_slice(&signature).ok()?;
+        let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+        serde_json::from_slice(&payload).ok()
+    }
+
+    fn sign(&self, token: &TrackingToken) -> String {
+        let payload = base64::encode_config(
+            serde_json::to_vec(token).expect("Failed to serialize a tracking token."),
+            base64::URL_SAFE_NO_PAD,
+        );
+        let signature = self.mac(&payload).finalize().into_bytes();
+        format!(
+            "{}.{}",
+            payload,
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    // The key also signs session cookies: the prefix keeps
+    // one kind of signature from being passed off as the other.
+    fn mac(&self, payload: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.hmac_secret.expose_secret().as_bytes())
+            .expect("HMAC accepts keys of any length.");
+        mac.update(b"tracking:");
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+// Anchors, 'mailto:' links... are left alone
+fn is_web_link(url: &str) -> bool {
+    let url = url.trim_start().to_ascii_lowercase();
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Tracker, TrackingOptions, TrackingToken};
+    use secrecy::Secret;
+    use uuid::Uuid;
+
+    fn tracker() -> Tracker {
+        Tracker::new("https://news.example.com".into(), Secret::new("secret".into()))
+    }
+
+    fn options(opens: bool, clicks: bool) -> TrackingOptions {
+        TrackingOptions { opens, clicks }
+    }
+
+    // the tokens in the tracking URLs of 'html'
+    fn tokens<'a>(html: &'a str, prefix: &str) -> Vec<&'a str> {
+        html.split(prefix)
+            .skip(1)
+            .map(|rest| rest.split(['"', '\'']).next().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn tokens_round_trip() {
+        let tracker = tracker();
+        let token = TrackingToken {
+            newsletter_issue_id: Uuid::new_v4(),
+            subscriber_id: Uuid::new_v4(),
+            url: Some("https://example.com/?a=1".into()),
+        };
+        assert_eq!(tracker.verify(&tracker.sign(&token)), Some(token));
+    }
+
+    #[test]
+    fn tampered_or_foreign_tokens_are_rejected() {
+        let token = TrackingToken {
+            newsletter_issue_id: Uuid::new_v4(),
+            subscriber_id: Uuid::new_v4(),
+            url: Some("https://example.com".into()),
+        };
+        let signed = tracker().sign(&token);
+        let other = Tracker::new("https://news.example.com".into(), Secret::new("other".into()));
+        assert_eq!(other.verify(&signed), None);
+
+        let (_, signature) = signed.split_once('.').unwrap();
+        let forged = TrackingToken {
+            url: Some("https://evil.example.com".into()),
+            ..token
+        };
+        let payload = base64::encode_config(
+            serde_json::to_vec(&forged).unwrap(),
+            base64::URL_SAFE_NO_PAD,
+        );
+        assert_eq!(tracker().verify(&format!("{}.{}", payload, signature)), None);
+        assert_eq!(tracker().verify("not-a-token"), None);
+    }
+
+    #[test]
+    fn web_links_are_tracked_when_clicks_are() {
+        let tracker = tracker();
+        let issue_id = Uuid::new_v4();
+        let subscriber_id = Uuid::new_v4();
+        let html = r##"<a href="https://example.com">a</a> <a href="mailto:x@example.com">b</a> <a href="#top">c</a>"##;
+        let tracked = tracker.instrument(html, options(false, true), issue_id, subscriber_id);
+        let tokens = tokens(&tracked, "https://news.example.com/t/c/");
+        assert_eq!(tokens.len(), 1);
+        let token = tracker.verify(tokens[0]).unwrap();
+        assert_eq!(token.newsletter_issue_id, issue_id);
+        assert_eq!(token.subscriber_id, subscriber_id);
+        assert_eq!(token.url.as_deref(), Some("https://example.com"));
+        assert!(tracked.contains(r#"href="mailto:x@example.com""#));
+        assert!(tracked.contains("href=\"#top\""));
+        assert!(!tracked.contains("<img"));
+    }
+
+    #[test]
+    fn a_pixel_is_added_when_opens_are_tracked() {
+        let tracker = tracker();
+        let html = r#"<p><a href="https://example.com">a</a></p>"#;
+        let tracked = tracker.instrument(html, options(true, false), Uuid::new_v4(), Uuid::new_v4());
+        assert!(tracked.starts_with(html));
+        let tokens = tokens(&tracked, "https://news.example.com/t/o/");
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tracker.verify(tokens[0]).unwrap().url, None);
+    }
+
+    #[test]
+    fn nothing_changes_without_tracking() {
+        let html = r#"<p><a href="https://example.com">a</a></p>"#;
+        let tracked = tracker().instrument(html, options(false, false), Uuid::new_v4(), Uuid::new_v4());
+        assert_eq!(tracked, html);
+    }
+}
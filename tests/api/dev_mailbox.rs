@@ -0,0 +1,110 @@
+use crate::helpers::{spawn_app, spawn_app_with};
+use uuid::Uuid;
+use zero2Prod::configuration::{get_configuration, DevMailboxSettings, EmailProvider};
+
+#[tokio::test]
+async fn the_dev_mailbox_lists_the_confirmation_link() {
+    // Arrange
+    let directory = std::env::temp_dir().join(Uuid::new_v4().to_string());
+    let app = spawn_app_with(|c| {
+        c.email_client.provider = EmailProvider::DevMailbox;
+        c.email_client.dev_mailbox = Some(DevMailboxSettings {
+            directory: directory.to_str().unwrap().into(),
+        });
+    })
+    .await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    // Act - Part 1 - Subscribe
+    let response = app.post_subscriptions(body.into()).await;
+    assert_eq!(200, response.status().as_u16());
+
+    // Act - Part 2 - Check the mailbox
+    let response = reqwest::get(&format!("{}/dev/mailbox", &app.address))
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let html = response.text().await.unwrap();
+    assert!(html.contains("ursula_le_guin@gmail.com"));
+    assert!(html.contains(&format!(
+        r#"<a href="{}/subscriptions/confirm?subscription_token="#,
+        app.base_url
+    )));
+    // Nothing was sent to Postmark
+    assert!(app.email_server.received_requests().await.unwrap().is_empty());
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[tokio::test]
+async fn the_dev_mailbox_does_not_exist_with_other_providers() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::get(&format!("{}/dev/mailbox", &app.address))
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(404, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn the_local_base_url_points_at_the_local_server() {
+    // Arrange
+    let configuration = get_configuration().expect("Failed to read configuration.");
+
+    // Act
+    let base_url = reqwest::Url::parse(&configuration.application.base_url).unwrap();
+
+    // Assert
+    assert_eq!(base_url.host_str(), Some(configuration.application.host.as_str()));
+    assert_eq!(base_url.port_or_known_default(), Some(configuration.application.port));
+}
+
+#[tokio::test]
+async fn confirmation_links_in_the_dev_mailbox_can_be_followed() {
+    // Arrange
+    let directory = std::env::temp_dir().join(Uuid::new_v4().to_string());
+    let app = spawn_app_with(|c| {
+        c.email_client.provider = EmailProvider::DevMailbox;
+        c.email_client.dev_mailbox = Some(DevMailboxSettings {
+            directory: directory.to_str().unwrap().into(),
+        });
+    })
+    .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+    let html = reqwest::get(&format!("{}/dev/mailbox", &app.address))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let link = linkify::LinkFinder::new()
+        .links(&html)
+        .map(|l| l.as_str())
+        .find(|l| l.contains("/subscriptions/confirm"))
+        .expect("No confirmation link in the dev mailbox.");
+    let mut link = reqwest::Url::parse(link).unwrap();
+    // The link points at the port from the configuration,
+    // our test instance listens on the one the OS gave it
+    assert!(link.as_str().starts_with(&app.base_url));
+    link.set_port(Some(app.port)).unwrap();
+
+    // Act
+    let response = reqwest::get(link).await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "confirmed");
+    std::fs::remove_dir_all(directory).unwrap();
+}
@@ -1,9 +1,20 @@
-use std::net::TcpListener;
 use once_cell::sync::Lazy;
 use sqlx::{Connection,Executor, PgConnection, PgPool};
-use zero2Prod::configuration::{get_configuration, DatabaseSettings};
+use zero2Prod::configuration::{
+    get_configuration, DatabaseSettings, EmailProvider, Settings, WebhookCredentials,
+};
 use sqlx::types::Uuid;
-use zero2Prod::email_client::EmailClient;
+use secrecy::{ExposeSecret, Secret};
+use wiremock::MockServer;
+use zero2Prod::authentication::compute_password_hash;
+use std::sync::Arc;
+use zero2Prod::email_client::EmailSender;
+use zero2Prod::email_templates::EmailTemplates;
+use zero2Prod::issue_delivery_worker::{
+    enqueue_due_issues, try_execute_task, ExecutionOutcome, RetryPolicy,
+};
+use zero2Prod::startup::{get_connection_pool, Application};
+use zero2Prod::tracking::Tracker;
 use zero2Prod::telemetry::{get_subscriber, init_subscriber};
 
 // Ensure that the 'tracing' stack is only initialised once using 'once_cell'
@@ -27,60 +38,437 @@ static TRACING: Lazy<()> = Lazy::new(|| {
     };
 });
 
+// An admin with known credentials, created for each test
+pub struct TestUser {
+    pub user_id: Uuid,
+    pub username: String,
+    pub password: String,
+}
+
+impl TestUser {
+    pub fn generate() -> Self {
+        Self {
+            user_id: Uuid::new_v4(),
+            username: Uuid::new_v4().to_string(),
+            password: Uuid::new_v4().to_string(),
+        }
+    }
+
+    async fn store(&self, pool: &PgPool) {
+        let password_hash = compute_password_hash(Secret::new(self.password.clone())).unwrap();
+        sqlx::query!(
+            "INSERT INTO users (user_id, username, password_hash)
+            VALUES ($1, $2, $3)",
+            self.user_id,
+            self.username,
+            password_hash.expose_secret(),
+        )
+        .execute(pool)
+        .await
+        .expect("Failed to store test user.");
+    }
+}
+
 // Generalise spawn_App
 pub struct TestApp {
     pub address: String,
+    pub port: u16,
     pub db_pool: PgPool,
+    // stands in for Postmark's API
+    pub email_server: MockServer,
+    pub email_client: Arc<dyn EmailSender>,
+    pub base_url: String,
+    pub retry_policy: RetryPolicy,
+    pub batch_size: usize,
+    pub email_templates: EmailTemplates,
+    pub tracker: Tracker,
+    pub webhook_credentials: WebhookCredentials,
+    pub test_user: TestUser,
+    // keeps the session cookie between requests and
+    // lets us inspect redirects instead of following them
+    pub api_client: reqwest::Client,
 }
 
+// Answers Postmark's '/email/batch' with one result per message:
+// every message is accepted, except those sent to 'rejected'.
+pub struct PostmarkBatchResponder {
+    rejected: Option<String>,
+}
+
+impl PostmarkBatchResponder {
+    pub fn accept_all() -> Self {
+        Self { rejected: None }
+    }
+
+    pub fn rejecting(email: &str) -> Self {
+        Self {
+            rejected: Some(email.into()),
+        }
+    }
+}
+
+impl wiremock::Respond for PostmarkBatchResponder {
+    fn respond(&self, request: &wiremock::Request) -> wiremock::ResponseTemplate {
+        let messages: Vec<serde_json::Value> = serde_json::from_slice(&request.body).unwrap();
+        let results: Vec<_> = messages
+            .iter()
+            .map(|message| match &self.rejected {
+                Some(rejected) if message["To"] == rejected.as_str() => serde_json::json!({
+                    "ErrorCode": 406,
+                    "Message": "You tried to send to a recipient that has been marked as inactive.",
+                }),
+                _ => serde_json::json!({"ErrorCode": 0, "Message": "OK"}),
+            })
+            .collect();
+        wiremock::ResponseTemplate::new(200).set_body_json(results)
+    }
+}
+
+// Confirmation links embedded in the request to the email API.
+pub struct ConfirmationLinks {
+    pub html: reqwest::Url,
+    pub plain_text: reqwest::Url,
+}
+
+impl TestApp {
+    // Drain the delivery queue, the way the background worker would
+    pub async fn dispatch_all_pending_emails(&self) {
+        loop {
+            if let ExecutionOutcome::EmptyQueue =
+                try_execute_task(
+                    &self.db_pool,
+                    self.email_client.as_ref(),
+                    &self.email_templates,
+                    &self.tracker,
+                    &self.base_url,
+                    &self.retry_policy,
+                    self.batch_size,
+                )
+                .await
+                .unwrap()
+            {
+                break;
+            }
+        }
+    }
+
+    // One pass of the scheduler, the way the background worker would
+    pub async fn enqueue_due_issues(&self) -> usize {
+        enqueue_due_issues(&self.db_pool).await.unwrap()
+    }
+
+    pub async fn post_subscriptions(&self, body: String) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(&format!("{}/subscriptions", &self.address))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_resend_confirmation(&self, body: String) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(&format!("{}/subscriptions/resend_confirmation", &self.address))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    // Mimics what mailbox providers do on a one-click unsubscribe (RFC 8058)
+    pub async fn post_unsubscribe(&self, unsubscribe_token: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(&format!(
+                "{}/subscriptions/unsubscribe?unsubscribe_token={}",
+                &self.address, unsubscribe_token
+            ))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body("List-Unsubscribe=One-Click")
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_newsletters(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(&format!("{}/newsletters", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    // Publish an issue and return its id, as reported back to the editor
+    pub async fn publish_newsletter(&self, body: serde_json::Value) -> Uuid {
+        let report: serde_json::Value = self
+            .post_newsletters(body)
+            .await
+            .error_for_status()
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        report["newsletter_issue_id"].as_str().unwrap().parse().unwrap()
+    }
+
+    pub async fn put_newsletter_schedule(
+        &self,
+        newsletter_issue_id: Uuid,
+        body: serde_json::Value,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .put(&format!("{}/newsletters/{}/schedule", &self.address, newsletter_issue_id))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_cancel_newsletter(&self, newsletter_issue_id: Uuid) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(&format!("{}/newsletters/{}/cancel", &self.address, newsletter_issue_id))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_preview_newsletter(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(&format!("{}/newsletters/preview", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_postmark_webhook(&self, event: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(&format!("{}/webhooks/postmark", &self.address))
+            .basic_auth(
+                &self.webhook_credentials.username,
+                Some(self.webhook_credentials.secret.expose_secret()),
+            )
+            .json(&event)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_newsletters_with_idempotency_key(
+        &self,
+        body: serde_json::Value,
+        idempotency_key: &str,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(&format!("{}/newsletters", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .header("Idempotency-Key", idempotency_key)
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_dead_letters(&self) -> Vec<serde_json::Value> {
+        reqwest::Client::new()
+            .get(&format!("{}/newsletters/dead_letters", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+            .json()
+            .await
+            .unwrap()
+    }
+
+    pub async fn post_login<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(&format!("{}/login", &self.address))
+            // This 'reqwest' method makes sure that the body is URL-encoded
+            // and the 'Content-Type' header is set accordingly.
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_login_html(&self, query: &str) -> String {
+        self.api_client
+            .get(&format!("{}/login{}", &self.address, query))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn get_admin_dashboard(&self) -> reqwest::Response {
+        self.api_client
+            .get(&format!("{}/admin/dashboard", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_admin_dashboard_html(&self) -> String {
+        self.get_admin_dashboard().await.text().await.unwrap()
+    }
+
+    // 'query' is appended as is, e.g. '?format=csv'
+    pub async fn get_newsletter_stats(&self, newsletter_issue_id: Uuid, query: &str) -> reqwest::Response {
+        self.api_client
+            .get(&format!(
+                "{}/admin/newsletters/{}/stats{}",
+                &self.address, newsletter_issue_id, query
+            ))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_logout(&self) -> reqwest::Response {
+        self.api_client
+            .post(&format!("{}/admin/logout", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn login_test_user(&self) -> reqwest::Response {
+        self.post_login(&serde_json::json!({
+            "username": &self.test_user.username,
+            "password": &self.test_user.password
+        }))
+        .await
+    }
+
+    // The first tracking URL of the given kind ('o' or 'c') in 'html',
+    // pointing at our test instance.
+    pub fn tracking_url(&self, html: &str, kind: &str) -> String {
+        let prefix = format!("{}/t/{}/", self.base_url, kind);
+        let start = html.find(&prefix).expect("No tracking URL in the email.");
+        let token = html[start + prefix.len()..].split('"').next().unwrap();
+        format!("{}/t/{}/{}", self.address, kind, token)
+    }
+
+    // Extract the confirmation links embedded in the request to the email API.
+    pub fn get_confirmation_links(
+        &self,
+        email_request: &wiremock::Request
+    ) -> ConfirmationLinks {
+        let body: serde_json::Value = serde_json::from_slice(
+            &email_request.body
+        ).unwrap();
 
+        // Extract the link from one of the request fields.
+        let get_link = |s: &str| {
+            let links: Vec<_> = linkify::LinkFinder::new()
+                .links(s)
+                .filter(|l| *l.kind() == linkify::LinkKind::Url)
+                .collect();
+            assert_eq!(links.len(), 1);
+            let raw_link = links[0].as_str().to_owned();
+            let mut confirmation_link = reqwest::Url::parse(&raw_link).unwrap();
+            // Let's make sure we don't call random APIs on the web
+            assert_eq!(confirmation_link.host_str().unwrap(), "127.0.0.1");
+            // The link points at the default port, rewrite it to the one
+            // the OS gave to our test instance
+            confirmation_link.set_port(Some(self.port)).unwrap();
+            confirmation_link
+        };
+
+        let html = get_link(body["HtmlBody"].as_str().unwrap());
+        let plain_text = get_link(body["TextBody"].as_str().unwrap());
+        ConfirmationLinks {
+            html,
+            plain_text
+        }
+    }
+}
 
 // Launch our application in the background ~somehow~
 
-// No .await call, therefore no need for `spawn_app` to be async now.
 // We are also running tests, so it is not worth it to propagate errors:
 // if we fail to perform the required setup we can just panic and crash
 // all the things.
 
 //public now!
 pub async fn spawn_app() -> TestApp {
+    spawn_app_with(|_| {}).await
+}
+
+// For the few tests that need a different configuration,
+// e.g. another email provider.
+pub async fn spawn_app_with(customise: impl FnOnce(&mut Settings)) -> TestApp {
     // the first time 'initialize' is invoked the code in 'TRAICNG' is executed.
     // All other invocations will instead skip execution.
     Lazy::force(&TRACING);
 
-    //zero2Prod::run().await
-    let listener = TcpListener::bind("127.0.0.1:0")
-        .expect("Failed to bind random port");
-    // We retrieve the port assigned to us by the OS
-    let port = listener.local_addr().unwrap().port();
-
-    let address = format!("http://127.0.0.1:{}", port);
-    let mut configuration= get_configuration().expect("Failed to read configuration.");
-    configuration.database.database_name = Uuid::new_v4().to_string();
-    let connection_pool = configure_database(&configuration.database).await;
-    /*
-    let connection_pool = PgPool::connect(&configuration.database.connection_string())
-        .await
-        .expect("Failed to connect to Postgres.");
-    */
+    // Launch a mock server to stand in for Postmark's API
+    let email_server = MockServer::start().await;
+
+    // Randomise configuration to ensure test isolation
+    let configuration = {
+        let mut c = get_configuration().expect("Failed to read configuration.");
+        // Use a different database for each test case
+        c.database.database_name = Uuid::new_v4().to_string();
+        // Use a random OS port
+        c.application.port = 0;
+        // Use the mock server as email API
+        c.email_client.base_url = reqwest::Url::parse(&email_server.uri()).unwrap();
+        // 'local.yaml' picks the dev mailbox: tests talk to the mock server
+        c.email_client.provider = EmailProvider::Postmark;
+        customise(&mut c);
+        c
+    };
 
-    //Build new email client
-    let sender_email = configuration.email_client.sender().expect("Invalid sender email address.");
-    let timeout = configuration.email_client.timeout();
-    let email_client = EmailClient::new(configuration.email_client.base_url, sender_email, configuration.email_client.authorization_token, timeout);
+    // Create and migrate the database
+    configure_database(&configuration.database).await;
 
-    let server = zero2Prod::startup::run(listener, connection_pool.clone(), email_client)
-        .expect("Failed to bind address");
-    // Launch the server as a background task
+    // Launch the application as a background task
+    let application = Application::build(configuration.clone())
+        .await
+        .expect("Failed to build application.");
+    // Get the port before spawning the application
+    let application_port = application.port();
     // tokio::spawn returns a handle to the spawned future,
     // but we have no use for it here, hence the non-binding let
-    let _ = tokio::spawn(server);
-    TestApp {
-        address,
-        db_pool: connection_pool,
-    }
-    // We return the application address to the caller!
-    //format!("http://127.0.0.1:{}", port)
+    let _ = tokio::spawn(application.run_until_stopped());
+
+    let test_app = TestApp {
+        address: format!("http://127.0.0.1:{}", application_port),
+        port: application_port,
+        db_pool: get_connection_pool(&configuration.database),
+        email_server,
+        email_client: configuration.email_client.client(),
+        base_url: configuration.application.base_url.clone(),
+        retry_policy: configuration.issue_delivery.retry_policy(),
+        batch_size: configuration.issue_delivery.batch_size,
+        email_templates: configuration.email_templates.load().unwrap(),
+        tracker: Tracker::new(
+            configuration.application.base_url,
+            configuration.application.hmac_secret.clone(),
+        ),
+        webhook_credentials: configuration.webhooks.postmark.clone(),
+        test_user: TestUser::generate(),
+        api_client: reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .cookie_store(true)
+            .build()
+            .unwrap(),
+    };
+    test_app.test_user.store(&test_app.db_pool).await;
+    test_app
 }
 
 //not public anymore
@@ -105,3 +493,37 @@ async fn configure_database(config: &DatabaseSettings) -> PgPool {
         .expect("Failed to migrate the database");
     connection_pool
 }
+
+// Subscribe and follow the confirmation link, through the public API
+pub async fn create_confirmed_subscriber(app: &TestApp, email: &str) {
+    let body = format!("name=le%20guin&email={}", email.replace('@', "%40"));
+    subscribe_and_confirm(app, body).await
+}
+
+// Same, with the subscription form of our choosing
+pub async fn subscribe_and_confirm(app: &TestApp, body: String) {
+    let _mock_guard = wiremock::Mock::given(wiremock::matchers::path("/email"))
+        .and(wiremock::matchers::method("POST"))
+        .respond_with(wiremock::ResponseTemplate::new(200))
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.post_subscriptions(body).await.error_for_status().unwrap();
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let confirmation_links = app.get_confirmation_links(&email_request);
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+}
+
+pub fn assert_is_redirect_to(response: &reqwest::Response, location: &str) {
+    assert_eq!(response.status().as_u16(), 303);
+    assert_eq!(response.headers().get("Location").unwrap(), location);
+}
@@ -0,0 +1,94 @@
+use crate::helpers::{assert_is_redirect_to, spawn_app};
+
+#[tokio::test]
+async fn an_error_message_is_shown_on_failure() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act - Part 1 - Try to login
+    let login_body = serde_json::json!({
+        "username": "random-username",
+        "password": "random-password"
+    });
+    let response = app.post_login(&login_body).await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/login?error=invalid_credentials");
+
+    // Act - Part 2 - Follow the redirect
+    let html_page = app.get_login_html("?error=invalid_credentials").await;
+    assert!(html_page.contains("<p><i>Authentication failed.</i></p>"));
+
+    // Act - Part 3 - The message is gone without the error code
+    let html_page = app.get_login_html("").await;
+    assert!(!html_page.contains("Authentication failed"));
+}
+
+#[tokio::test]
+async fn arbitrary_error_messages_are_not_rendered() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let html_page = app.get_login_html("?error=<script>alert(1)</script>").await;
+
+    // Assert
+    assert!(!html_page.contains("<script>"));
+}
+
+#[tokio::test]
+async fn redirect_to_admin_dashboard_after_login_success() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act - Part 1 - Login
+    let response = app.login_test_user().await;
+    assert_is_redirect_to(&response, "/admin/dashboard");
+
+    // Act - Part 2 - Follow the redirect
+    let html_page = app.get_admin_dashboard_html().await;
+    assert!(html_page.contains(&format!("Welcome {}", app.test_user.username)));
+}
+
+#[tokio::test]
+async fn sessions_are_stored_in_postgres() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    app.login_test_user().await;
+
+    // Assert
+    let sessions = sqlx::query!("SELECT state FROM sessions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(sessions.len(), 1);
+    assert!(sessions[0].state.contains(&app.test_user.user_id.to_string()));
+}
+
+#[tokio::test]
+async fn the_session_key_is_rotated_on_login() {
+    // Arrange
+    let app = spawn_app().await;
+    app.login_test_user().await;
+    let first_session_key = sqlx::query!("SELECT session_key FROM sessions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .session_key;
+
+    // Act - Login again, with the first session cookie still set
+    app.login_test_user().await;
+
+    // Assert
+    let session_keys: Vec<String> = sqlx::query!("SELECT session_key FROM sessions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| r.session_key)
+        .collect();
+    assert_eq!(session_keys.len(), 1);
+    assert_ne!(session_keys[0], first_session_key);
+}
@@ -1,4 +1,15 @@
+mod admin_dashboard;
+mod dev_mailbox;
 mod helpers;
 mod health_check;
+mod login;
+mod newsletter_schedule;
+mod newsletter_stats;
+mod newsletters;
 mod subscriptions;
+mod subscriptions_confirm;
+mod subscriptions_resend_confirmation;
+mod subscriptions_unsubscribe;
+mod tracking;
+mod webhooks;
 
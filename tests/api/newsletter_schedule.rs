@@ -0,0 +1,237 @@
+use crate::helpers::{
+    create_confirmed_subscriber, spawn_app, subscribe_and_confirm, PostmarkBatchResponder, TestApp,
+};
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+fn scheduled_newsletter(scheduled_for: DateTime<Utc>) -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        },
+        "scheduled_for": scheduled_for,
+    })
+}
+
+async fn n_queued(app: &TestApp) -> i64 {
+    sqlx::query!("SELECT COUNT(*) AS \"n!\" FROM issue_delivery_queue")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .n
+}
+
+#[tokio::test]
+async fn scheduled_issues_are_only_queued_once_due() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "ursula_le_guin@gmail.com").await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::accept_all())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act - Part 1 - Schedule the issue for later
+    let response = app
+        .post_newsletters(scheduled_newsletter(Utc::now() + Duration::hours(1)))
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(report["queued"], 0);
+    assert!(report["scheduled_for"].is_string());
+    let issue_id: Uuid = report["newsletter_issue_id"].as_str().unwrap().parse().unwrap();
+
+    // Act - Part 2 - Nothing happens before it is due
+    assert_eq!(app.enqueue_due_issues().await, 0);
+    app.dispatch_all_pending_emails().await;
+    assert_eq!(n_queued(&app).await, 0);
+
+    // Act - Part 3 - Move it to the past
+    let response = app
+        .put_newsletter_schedule(
+            issue_id,
+            serde_json::json!({ "scheduled_for": Utc::now() - Duration::minutes(1) }),
+        )
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    // Assert
+    assert_eq!(app.enqueue_due_issues().await, 1);
+    assert_eq!(n_queued(&app).await, 1);
+    app.dispatch_all_pending_emails().await;
+    // Queued once only
+    assert_eq!(app.enqueue_due_issues().await, 0);
+}
+
+#[tokio::test]
+async fn rescheduling_without_a_time_sends_the_issue_right_away() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "ursula_le_guin@gmail.com").await;
+    let issue_id = app
+        .publish_newsletter(scheduled_newsletter(Utc::now() + Duration::days(3)))
+        .await;
+
+    // Act
+    let response = app
+        .put_newsletter_schedule(issue_id, serde_json::json!({}))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(app.enqueue_due_issues().await, 1);
+    assert_eq!(n_queued(&app).await, 1);
+}
+
+#[tokio::test]
+async fn cancelled_issues_are_never_sent() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "ursula_le_guin@gmail.com").await;
+
+    Mock::given(path("/email/batch"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    let issue_id = app
+        .publish_newsletter(scheduled_newsletter(Utc::now() + Duration::minutes(1)))
+        .await;
+
+    // Act
+    let response = app.post_cancel_newsletter(issue_id).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    // Moving it to the past does not bring it back
+    let response = app
+        .put_newsletter_schedule(issue_id, serde_json::json!({}))
+        .await;
+    assert_eq!(response.status().as_u16(), 409);
+    assert_eq!(app.enqueue_due_issues().await, 0);
+    app.dispatch_all_pending_emails().await;
+}
+
+#[tokio::test]
+async fn queued_issues_can_no_longer_be_rescheduled_or_cancelled() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "ursula_le_guin@gmail.com").await;
+    // Not scheduled: queued right away
+    let issue_id = app
+        .publish_newsletter(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            },
+        }))
+        .await;
+
+    // Act
+    let reschedule = app
+        .put_newsletter_schedule(
+            issue_id,
+            serde_json::json!({ "scheduled_for": Utc::now() + Duration::days(1) }),
+        )
+        .await;
+    let cancel = app.post_cancel_newsletter(issue_id).await;
+
+    // Assert
+    assert_eq!(reschedule.status().as_u16(), 409);
+    assert_eq!(cancel.status().as_u16(), 409);
+    assert_eq!(n_queued(&app).await, 1);
+}
+
+#[tokio::test]
+async fn unknown_issues_cannot_be_rescheduled_or_cancelled() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let reschedule = app
+        .put_newsletter_schedule(Uuid::new_v4(), serde_json::json!({}))
+        .await;
+    let cancel = app.post_cancel_newsletter(Uuid::new_v4()).await;
+
+    // Assert
+    assert_eq!(reschedule.status().as_u16(), 404);
+    assert_eq!(cancel.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn sending_in_the_subscriber_timezone_needs_a_time() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            },
+            "send_in_subscriber_timezone": true,
+        }))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn issues_can_be_sent_at_the_same_time_in_every_subscriber_timezone() {
+    // Arrange
+    let app = spawn_app().await;
+    // UTC+9, no daylight saving time
+    subscribe_and_confirm(
+        &app,
+        "name=Haruki&email=haruki%40example.com&timezone=Asia%2FTokyo".into(),
+    )
+    .await;
+    // Without a timezone: UTC
+    create_confirmed_subscriber(&app, "ursula_le_guin@gmail.com").await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::accept_all())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    // Two hours from now, read in UTC: already past in Tokyo
+    let local_time = (Utc::now() + Duration::hours(2))
+        .duration_trunc(Duration::minutes(1))
+        .unwrap();
+
+    // Act
+    let mut newsletter = scheduled_newsletter(local_time);
+    newsletter["send_in_subscriber_timezone"] = true.into();
+    app.post_newsletters(newsletter)
+        .await
+        .error_for_status()
+        .unwrap();
+    assert_eq!(app.enqueue_due_issues().await, 1);
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let queued = sqlx::query!("SELECT subscriber_email, execute_after FROM issue_delivery_queue")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    // Sent in Tokyo, still waiting in UTC
+    assert_eq!(queued.len(), 1);
+    assert_eq!(queued[0].subscriber_email, "ursula_le_guin@gmail.com");
+    assert_eq!(queued[0].execute_after, local_time);
+    let batch = app.email_server.received_requests().await.unwrap().pop().unwrap();
+    let batch: serde_json::Value = serde_json::from_slice(&batch.body).unwrap();
+    assert_eq!(batch.as_array().unwrap().len(), 1);
+    assert_eq!(batch[0]["To"], "haruki@example.com");
+}
@@ -0,0 +1,170 @@
+use crate::helpers::{
+    assert_is_redirect_to, create_confirmed_subscriber, spawn_app, PostmarkBatchResponder,
+};
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::Mock;
+
+fn tracked_newsletter() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "markdown": "Hello, read [the docs](https://example.com/docs).",
+        },
+        "tracking": { "opens": true, "clicks": true },
+    })
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_see_the_stats_of_an_issue() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app.get_newsletter_stats(Uuid::new_v4(), "").await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn stats_of_an_unknown_issue_are_a_404() {
+    // Arrange
+    let app = spawn_app().await;
+    app.login_test_user().await;
+
+    // Act
+    let response = app.get_newsletter_stats(Uuid::new_v4(), "").await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn queued_deliveries_are_counted_before_they_go_out() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "ursula_le_guin@gmail.com").await;
+    create_confirmed_subscriber(&app, "octavia_butler@gmail.com").await;
+    let issue_id = app.publish_newsletter(tracked_newsletter()).await;
+    app.login_test_user().await;
+
+    // Act
+    let response = app.get_newsletter_stats(issue_id, "").await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let stats: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(stats["title"], "Newsletter title");
+    assert_eq!(
+        stats["totals"],
+        serde_json::json!({
+            "queued": 2, "sent": 0, "failed": 0, "bounced": 0, "opened": 0, "clicked": 0
+        })
+    );
+    // Nothing happened yet
+    assert_eq!(stats["time_series"], serde_json::json!([]));
+}
+
+#[tokio::test]
+async fn stats_aggregate_deliveries_failures_bounces_opens_and_clicks() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "ursula_le_guin@gmail.com").await;
+    create_confirmed_subscriber(&app, "octavia_butler@gmail.com").await;
+    create_confirmed_subscriber(&app, "inactive@example.com").await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::rejecting("inactive@example.com"))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let issue_id = app.publish_newsletter(tracked_newsletter()).await;
+    app.dispatch_all_pending_emails().await;
+
+    let batch = app.email_server.received_requests().await.unwrap().pop().unwrap();
+    let batch: serde_json::Value = serde_json::from_slice(&batch.body).unwrap();
+    let ursula = batch
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|message| message["To"] == "ursula_le_guin@gmail.com")
+        .unwrap();
+    // Postmark sends the metadata back with bounces
+    assert_eq!(ursula["Metadata"]["newsletter_issue_id"], issue_id.to_string());
+    let html = ursula["HtmlBody"].as_str().unwrap();
+    // Opened twice, clicked once
+    for url in [
+        app.tracking_url(html, "o"),
+        app.tracking_url(html, "o"),
+        app.tracking_url(html, "c"),
+    ] {
+        app.api_client.get(url).send().await.unwrap();
+    }
+    app.post_postmark_webhook(serde_json::json!({
+        "RecordType": "Bounce",
+        "Type": "SoftBounce",
+        "Email": "octavia_butler@gmail.com",
+        "Metadata": { "newsletter_issue_id": issue_id.to_string() },
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+    app.login_test_user().await;
+
+    // Act
+    let response = app.get_newsletter_stats(issue_id, "").await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let stats: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        stats["totals"],
+        serde_json::json!({
+            "queued": 0, "sent": 2, "failed": 1, "bounced": 1, "opened": 1, "clicked": 1
+        })
+    );
+    assert_eq!(stats["bucket"], "hour");
+    // Opens and clicks are events in the time series
+    let series = stats["time_series"].as_array().unwrap();
+    let total = |field: &str| -> i64 { series.iter().map(|b| b[field].as_i64().unwrap()).sum() };
+    assert_eq!(
+        (total("sent"), total("failed"), total("bounced"), total("opened"), total("clicked")),
+        (2, 1, 1, 2, 1)
+    );
+}
+
+#[tokio::test]
+async fn stats_can_be_exported_as_csv() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "ursula_le_guin@gmail.com").await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::accept_all())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let issue_id = app.publish_newsletter(tracked_newsletter()).await;
+    app.dispatch_all_pending_emails().await;
+    app.login_test_user().await;
+
+    // Act
+    let response = app
+        .get_newsletter_stats(issue_id, "?format=csv&bucket=day")
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response.headers()["Content-Type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/csv"));
+    let csv = response.text().await.unwrap();
+    let lines: Vec<_> = csv.lines().collect();
+    assert_eq!(lines[0], "period,queued,sent,failed,bounced,opened,clicked");
+    assert_eq!(lines[1], "total,0,1,0,0,0,0");
+    // A single day, with an empty 'queued' column
+    assert_eq!(lines.len(), 3);
+    assert!(lines[2].ends_with(",,1,0,0,0,0"));
+}
@@ -0,0 +1,270 @@
+use crate::helpers::{create_confirmed_subscriber, spawn_app, PostmarkBatchResponder, TestApp};
+use wiremock::matchers::{method, path};
+use wiremock::Mock;
+
+const EMAIL: &str = "ursula_le_guin@gmail.com";
+
+fn tracked_newsletter(opens: bool, clicks: bool) -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "markdown": "Hello, read [the docs](https://example.com/docs?a=1&b=2).",
+        },
+        "tracking": { "opens": opens, "clicks": clicks },
+    })
+}
+
+// Publish an issue and return the (HTML, plain text) bodies it was sent with
+async fn publish_and_deliver(app: &TestApp, newsletter: serde_json::Value) -> (String, String) {
+    let _mock_guard = Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::accept_all())
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.post_newsletters(newsletter)
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+    let batch = app.email_server.received_requests().await.unwrap().pop().unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&batch.body).unwrap();
+    (
+        body[0]["HtmlBody"].as_str().unwrap().to_string(),
+        body[0]["TextBody"].as_str().unwrap().to_string(),
+    )
+}
+
+async fn events(app: &TestApp) -> Vec<(String, String, Option<String>)> {
+    sqlx::query!("SELECT subscriber_email, kind, url FROM tracking_events ORDER BY occurred_at")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| (r.subscriber_email, r.kind, r.url))
+        .collect()
+}
+
+#[tokio::test]
+async fn issues_are_not_tracked_by_default() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, EMAIL).await;
+
+    // Act
+    let (html, _) = publish_and_deliver(
+        &app,
+        serde_json::json!({
+            "title": "Newsletter title",
+            "content": { "markdown": "Read [the docs](https://example.com/docs)." },
+        }),
+    )
+    .await;
+
+    // Assert
+    assert!(html.contains(r#"href="https://example.com/docs""#));
+    assert!(!html.contains("/t/c/"));
+    assert!(!html.contains("/t/o/"));
+}
+
+#[tokio::test]
+async fn tracked_issues_rewrite_links_and_embed_a_pixel() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, EMAIL).await;
+
+    // Act
+    let (html, text) = publish_and_deliver(&app, tracked_newsletter(true, true)).await;
+
+    // Assert
+    assert!(!html.contains(r#"href="https://example.com/docs"#));
+    assert!(html.contains(&format!("{}/t/c/", app.base_url)));
+    assert!(html.contains(&format!(r#"<img src="{}/t/o/"#, app.base_url)));
+    // The footer links are not tracked
+    assert!(html.contains(&format!("{}/subscriptions/unsubscribe?", app.base_url)));
+    assert!(html.contains(&format!("{}/subscriptions/tracking_opt_out?", app.base_url)));
+    // Neither is the plain-text version
+    assert!(text.contains("https://example.com/docs?a=1&b=2"));
+    assert!(!text.contains("/t/"));
+}
+
+#[tokio::test]
+async fn opens_and_clicks_can_be_tracked_separately() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, EMAIL).await;
+
+    // Act
+    let (opens_only, _) = publish_and_deliver(&app, tracked_newsletter(true, false)).await;
+    let (clicks_only, _) = publish_and_deliver(&app, tracked_newsletter(false, true)).await;
+
+    // Assert
+    assert!(opens_only.contains("/t/o/"));
+    assert!(!opens_only.contains("/t/c/"));
+    assert!(clicks_only.contains("/t/c/"));
+    assert!(!clicks_only.contains("/t/o/"));
+}
+
+#[tokio::test]
+async fn opens_are_recorded_and_get_a_pixel() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, EMAIL).await;
+    let (html, _) = publish_and_deliver(&app, tracked_newsletter(true, false)).await;
+
+    // Act
+    let response = reqwest::get(app.tracking_url(&html, "o")).await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()["Content-Type"], "image/gif");
+    assert!(!response.bytes().await.unwrap().is_empty());
+    assert_eq!(events(&app).await, vec![(EMAIL.into(), "open".into(), None)]);
+}
+
+#[tokio::test]
+async fn clicks_are_recorded_and_redirected_to_the_original_link() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, EMAIL).await;
+    let (html, _) = publish_and_deliver(&app, tracked_newsletter(false, true)).await;
+
+    // Act
+    let response = app
+        .api_client
+        .get(app.tracking_url(&html, "c"))
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 302);
+    assert_eq!(
+        response.headers()["Location"],
+        "https://example.com/docs?a=1&b=2"
+    );
+    assert_eq!(
+        events(&app).await,
+        vec![(
+            EMAIL.into(),
+            "click".into(),
+            Some("https://example.com/docs?a=1&b=2".into())
+        )]
+    );
+}
+
+#[tokio::test]
+async fn tracking_links_do_not_carry_the_subscriber_address() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, EMAIL).await;
+
+    // Act
+    let (html, _) = publish_and_deliver(&app, tracked_newsletter(true, true)).await;
+
+    // Assert
+    for kind in ["o", "c"] {
+        let url = app.tracking_url(&html, kind);
+        let (payload, _) = url.rsplit('/').next().unwrap().split_once('.').unwrap();
+        let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).unwrap();
+        let payload: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert!(!payload.to_string().contains(EMAIL));
+        let subscriber_id = sqlx::query!("SELECT id FROM subscriptions")
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap()
+            .id;
+        assert_eq!(payload["s"], subscriber_id.to_string());
+    }
+}
+
+#[tokio::test]
+async fn forged_tracking_links_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let click = app
+        .api_client
+        .get(format!("{}/t/c/eyJ1IjoiaHR0cHM6Ly9ldmlsLmV4YW1wbGUuY29tIn0.AAAA", app.address))
+        .send()
+        .await
+        .unwrap();
+    let open = reqwest::get(format!("{}/t/o/not-a-token", app.address))
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(click.status().as_u16(), 404);
+    // The image is served anyway
+    assert_eq!(open.status().as_u16(), 200);
+    assert!(events(&app).await.is_empty());
+}
+
+#[tokio::test]
+async fn subscribers_who_opted_out_are_not_tracked() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, EMAIL).await;
+    // An issue they got before opting out
+    let (before, _) = publish_and_deliver(&app, tracked_newsletter(true, true)).await;
+    let unsubscribe_token = sqlx::query!("SELECT unsubscribe_token FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .unsubscribe_token;
+
+    // Act
+    let form = reqwest::get(format!(
+        "{}/subscriptions/tracking_opt_out?unsubscribe_token={}",
+        app.address, unsubscribe_token
+    ))
+    .await
+    .unwrap();
+    assert_eq!(form.status().as_u16(), 200);
+    let response = reqwest::Client::new()
+        .post(format!(
+            "{}/subscriptions/tracking_opt_out?unsubscribe_token={}",
+            app.address, unsubscribe_token
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let (after, text) = publish_and_deliver(&app, tracked_newsletter(true, true)).await;
+
+    // Assert
+    assert!(!after.contains("/t/c/"));
+    assert!(!after.contains("/t/o/"));
+    assert!(after.contains(r#"href="https://example.com/docs?a=1&amp;b=2""#));
+    assert!(text.contains("https://example.com/docs?a=1&b=2"));
+    // Links sent before they opted out still work, but are not recorded
+    reqwest::get(app.tracking_url(&before, "o")).await.unwrap();
+    let click = app
+        .api_client
+        .get(app.tracking_url(&before, "c"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(click.status().as_u16(), 302);
+    assert!(events(&app).await.is_empty());
+}
+
+#[tokio::test]
+async fn opting_out_of_tracking_requires_a_valid_token() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!(
+            "{}/subscriptions/tracking_opt_out?unsubscribe_token=not-a-token",
+            app.address
+        ))
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+}
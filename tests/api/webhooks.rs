@@ -0,0 +1,320 @@
+use crate::helpers::{create_confirmed_subscriber, spawn_app, PostmarkBatchResponder, TestApp};
+use secrecy::ExposeSecret;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+fn hard_bounce(email: &str) -> serde_json::Value {
+    serde_json::json!({
+        "RecordType": "Bounce",
+        "Type": "HardBounce",
+        "TypeCode": 1,
+        "Email": email,
+        "Inactive": true,
+        "Description": "The server was unable to deliver your message (ex: unknown user, mailbox not found).",
+        "BouncedAt": "2022-12-11T10:00:00Z",
+    })
+}
+
+#[tokio::test]
+async fn webhooks_without_credentials_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/webhooks/postmark", &app.address))
+        .json(&hard_bounce("ursula_le_guin@gmail.com"))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!(0, n_suppressions(&app).await);
+}
+
+#[tokio::test]
+async fn webhooks_with_the_wrong_secret_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+
+    for request in [
+        reqwest::Client::new()
+            .post(format!("{}/webhooks/postmark", &app.address))
+            .basic_auth(&app.webhook_credentials.username, Some("not-the-secret")),
+        reqwest::Client::new()
+            .post(format!("{}/webhooks/postmark", &app.address))
+            .header("X-Webhook-Secret", "not-the-secret"),
+    ] {
+        // Act
+        let response = request
+            .json(&hard_bounce("ursula_le_guin@gmail.com"))
+            .send()
+            .await
+            .expect("Failed to execute request.");
+
+        // Assert
+        assert_eq!(401, response.status().as_u16());
+    }
+    assert_eq!(0, n_suppressions(&app).await);
+}
+
+#[tokio::test]
+async fn the_shared_secret_can_be_sent_in_a_header() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/webhooks/postmark", &app.address))
+        .header("X-Webhook-Secret", app.webhook_credentials.secret.expose_secret())
+        .json(&hard_bounce("ursula_le_guin@gmail.com"))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(1, n_suppressions(&app).await);
+}
+
+#[tokio::test]
+async fn hard_bounces_and_spam_complaints_are_suppressed() {
+    // Arrange
+    let app = spawn_app().await;
+    let spam_complaint = serde_json::json!({
+        "RecordType": "SpamComplaint",
+        "Type": "SpamComplaint",
+        "Email": "octavia_butler@gmail.com",
+    });
+
+    // Act
+    for event in [hard_bounce("ursula_le_guin@gmail.com"), spam_complaint] {
+        let response = app.post_postmark_webhook(event).await;
+        assert_eq!(200, response.status().as_u16());
+    }
+
+    // Assert
+    let saved = sqlx::query!("SELECT email, reason FROM suppressions ORDER BY email")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.len(), 2);
+    assert_eq!(saved[0].email, "octavia_butler@gmail.com");
+    assert_eq!(saved[0].reason, "spam_complaint");
+    assert_eq!(saved[1].email, "ursula_le_guin@gmail.com");
+    assert_eq!(saved[1].reason, "hard_bounce");
+}
+
+#[tokio::test]
+async fn soft_bounces_and_other_events_are_acknowledged_and_ignored() {
+    // Arrange
+    let app = spawn_app().await;
+    let soft_bounce = serde_json::json!({
+        "RecordType": "Bounce",
+        "Type": "SoftBounce",
+        "Email": "ursula_le_guin@gmail.com",
+        "Inactive": false,
+    });
+    let delivery = serde_json::json!({
+        "RecordType": "Delivery",
+        "Recipient": "ursula_le_guin@gmail.com",
+    });
+
+    for event in [soft_bounce, delivery] {
+        // Act
+        let response = app.post_postmark_webhook(event).await;
+
+        // Assert
+        assert_eq!(200, response.status().as_u16());
+    }
+    assert_eq!(0, n_suppressions(&app).await);
+}
+
+#[tokio::test]
+async fn invalid_payloads_are_rejected_with_a_400() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app
+        .post_postmark_webhook(serde_json::json!({"RecordType": "Bounce"}))
+        .await;
+
+    // Assert
+    assert_eq!(400, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn subscription_changes_suppress_and_reactivate_addresses() {
+    // Arrange
+    let app = spawn_app().await;
+    let change = |suppress_sending: bool| {
+        serde_json::json!({
+            "RecordType": "SubscriptionChange",
+            "Recipient": "ursula_le_guin@gmail.com",
+            "SuppressSending": suppress_sending,
+            "SuppressionReason": if suppress_sending { Some("ManualSuppression") } else { None },
+            "ChangedAt": "2022-12-11T10:00:00Z",
+        })
+    };
+
+    // Act - Part 1 - Suppress
+    app.post_postmark_webhook(change(true)).await;
+    let reason = sqlx::query!("SELECT reason FROM suppressions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .reason;
+    assert_eq!(reason, "manual");
+
+    // Act - Part 2 - Reactivate
+    app.post_postmark_webhook(change(false)).await;
+    assert_eq!(0, n_suppressions(&app).await);
+}
+
+#[tokio::test]
+async fn newsletters_are_not_delivered_to_suppressed_addresses() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "ursula_le_guin@gmail.com").await;
+    create_confirmed_subscriber(&app, "octavia_butler@gmail.com").await;
+    app.post_postmark_webhook(hard_bounce("ursula_le_guin@gmail.com"))
+        .await
+        .error_for_status()
+        .unwrap();
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::accept_all())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_newsletters(newsletter()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(report["queued"], 1);
+    let batch = app.email_server.received_requests().await.unwrap().pop().unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&batch.body).unwrap();
+    assert_eq!(body.as_array().unwrap().len(), 1);
+    assert_eq!(body[0]["To"], "octavia_butler@gmail.com");
+}
+
+#[tokio::test]
+async fn addresses_suppressed_after_publishing_are_skipped() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "ursula_le_guin@gmail.com").await;
+
+    Mock::given(path("/email/batch"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    app.post_newsletters(newsletter()).await.error_for_status().unwrap();
+
+    // Act
+    app.post_postmark_webhook(hard_bounce("ursula_le_guin@gmail.com"))
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let n_queued = sqlx::query!("SELECT COUNT(*) AS \"n!\" FROM issue_delivery_queue")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .n;
+    assert_eq!(n_queued, 0);
+}
+
+#[tokio::test]
+async fn suppressions_ignore_the_case_of_the_address() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "Ursula_Le_Guin@Gmail.com").await;
+
+    Mock::given(path("/email/batch"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act - Part 1 - Suppress
+    app.post_postmark_webhook(hard_bounce("ursula_le_guin@gmail.com"))
+        .await
+        .error_for_status()
+        .unwrap();
+    let response = app.post_newsletters(newsletter()).await;
+
+    // Assert
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(report["queued"], 0);
+
+    // Act - Part 2 - Reactivate
+    let change = serde_json::json!({
+        "RecordType": "SubscriptionChange",
+        "Recipient": "URSULA_LE_GUIN@GMAIL.COM",
+        "SuppressSending": false,
+        "ChangedAt": "2022-12-11T10:00:00Z",
+    });
+    app.post_postmark_webhook(change).await;
+    assert_eq!(0, n_suppressions(&app).await);
+}
+
+#[tokio::test]
+async fn recipients_rejected_by_the_provider_are_suppressed() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "Inactive@example.com").await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::rejecting("Inactive@example.com"))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act - Part 1 - The provider rejects the address
+    app.post_newsletters(newsletter()).await.error_for_status().unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let saved = sqlx::query!("SELECT email, reason FROM suppressions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.email, "inactive@example.com");
+    assert_eq!(saved.reason, "rejected");
+
+    // Act - Part 2 - The next issue is not even queued for it
+    let response = app.post_newsletters(newsletter()).await;
+
+    // Assert
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(report["queued"], 0);
+    // Mock verifies on Drop that the address was only tried once
+}
+
+fn newsletter() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    })
+}
+
+async fn n_suppressions(app: &TestApp) -> i64 {
+    sqlx::query!("SELECT COUNT(*) AS \"n!\" FROM suppressions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .n
+}